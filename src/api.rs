@@ -20,17 +20,180 @@
 
 #![allow(clippy::large_enum_variant)]
 
-use crate::Result;
+use crate::{Result, SendableError};
 use deepwell_core::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 
-pub const PROTOCOL_VERSION: &str = "0";
+pub const PROTOCOL_VERSION: &str = "1";
+
+/// Errors the server returns itself, without asking DEEPWELL.
+///
+/// They are sent as a `SendableError` named after the kind, such as `"server-busy"`.
+/// Use `ServerError::from_sendable()` to check whether an error is one of these.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ServerError {
+    /// A list argument has more items than the server allows.
+    RequestTooLarge,
+
+    /// The backend queue is full, and the server is set to reject requests rather than wait.
+    ServerBusy,
+
+    /// The server is in read-only mode, and the method would modify data.
+    ReadOnly,
+
+    /// The client's protocol version is below the server's minimum.
+    ProtocolTooOld,
+
+    /// The method has been disabled in the server's configuration.
+    MethodDisabled,
+
+    /// Too many calls to the method have been made recently.
+    RateLimited,
+
+    /// The user is not listed as an administrator in the server's configuration.
+    NotAdmin,
+
+    /// The server is shutting down, and not starting new requests.
+    ShuttingDown,
+
+    /// The client's deadline passed before the request could be processed.
+    DeadlinePassed,
+}
+
+impl ServerError {
+    pub fn name(self) -> &'static str {
+        use self::ServerError::*;
+
+        match self {
+            RequestTooLarge => "request-too-large",
+            ServerBusy => "server-busy",
+            ReadOnly => "read-only",
+            ProtocolTooOld => "protocol-too-old",
+            MethodDisabled => "method-disabled",
+            RateLimited => "rate-limited",
+            NotAdmin => "not-admin",
+            ShuttingDown => "shutting-down",
+            DeadlinePassed => "deadline-passed",
+        }
+    }
+
+    pub fn message(self) -> &'static str {
+        use self::ServerError::*;
+
+        match self {
+            RequestTooLarge => "request too large",
+            ServerBusy => "server is busy",
+            ReadOnly => "server is in read-only mode",
+            ProtocolTooOld => "client protocol too old",
+            MethodDisabled => "method disabled",
+            RateLimited => "rate limit exceeded",
+            NotAdmin => "user is not an administrator",
+            ShuttingDown => "server is shutting down",
+            DeadlinePassed => "request deadline has passed",
+        }
+    }
+
+    pub fn to_sendable(self) -> SendableError {
+        let error = json!({
+            "name": self.name(),
+            "message": self.message(),
+        });
+
+        serde_json::from_value(error).expect("Unable to build SendableError")
+    }
+
+    /// Returns which of these errors the given one is, if any.
+    #[allow(dead_code)] // only used by clients
+    pub fn from_sendable(error: &SendableError) -> Option<Self> {
+        const SERVER_ERRORS: [ServerError; 9] = [
+            ServerError::RequestTooLarge,
+            ServerError::ServerBusy,
+            ServerError::ReadOnly,
+            ServerError::ProtocolTooOld,
+            ServerError::MethodDisabled,
+            ServerError::RateLimited,
+            ServerError::NotAdmin,
+            ServerError::ShuttingDown,
+            ServerError::DeadlinePassed,
+        ];
+
+        SERVER_ERRORS
+            .iter()
+            .copied()
+            .find(|kind| kind.name() == error.name())
+    }
+}
+
+/// A user to create with `create_users`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NewUser {
+    pub name: String,
+    pub email: String,
+    pub password: String,
+}
+
+/// How long the server has been running.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct UptimeInfo {
+    /// The system time the server was started, in seconds since the Unix epoch.
+    pub start_time: f64,
+
+    /// The number of seconds since the server was started.
+    pub uptime: f64,
+}
+
+/// The most items the server accepts in a single call.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct BatchLimits {
+    /// The most items a list argument may have, such as the IDs for `get_users_from_ids`.
+    pub request_items: usize,
+}
+
+/// Whether the server is accepting new requests, such as for load balancer health checks.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct ReadinessInfo {
+    /// False once the server has begun shutting down.
+    pub ready: bool,
+
+    /// The number of requests currently being processed.
+    pub in_flight: u64,
+}
+
+/// A page's contents, unless the caller already has them.
+///
+/// The tag identifies the contents, and is only meaningful to the server which returned it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum PageContentsResult {
+    Changed { contents: String, tag: u64 },
+    NotModified,
+    Missing,
+}
+
+/// Notice that a session has been ended, such as by logging out.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SessionInvalidated {
+    pub session_id: SessionId,
+    pub user_id: UserId,
+}
+
+/// Events received from a poll, and the cursor to pass to the next one.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EventBatch<T> {
+    pub cursor: u64,
+    pub events: Vec<T>,
+}
 
 #[tarpc::service]
 pub trait Deepwell {
     // Misc
     async fn protocol() -> String;
+    async fn handshake(client_protocol: String) -> Result<String>;
     async fn ping() -> Result<()>;
     async fn time() -> f64;
+    async fn uptime() -> Result<UptimeInfo>;
+    async fn readiness() -> ReadinessInfo;
+    async fn batch_limits() -> Result<BatchLimits>;
 
     // Session
     async fn login(
@@ -42,6 +205,11 @@ pub trait Deepwell {
     async fn logout(session_id: SessionId, user_id: UserId) -> Result<()>;
     async fn logout_others(session_id: SessionId, user_id: UserId) -> Result<Vec<Session>>;
     async fn check_session(session_id: SessionId, user_id: UserId) -> Result<()>;
+    async fn poll_session_events(
+        session_id: SessionId,
+        user_id: UserId,
+        cursor: Option<u64>,
+    ) -> Result<EventBatch<SessionInvalidated>>;
 
     // User
     async fn create_user(name: String, email: String, password: String) -> Result<UserId>;
@@ -50,9 +218,85 @@ pub trait Deepwell {
     async fn get_users_from_ids(user_ids: Vec<UserId>) -> Result<Vec<Option<User>>>;
     async fn get_user_from_name(name: String) -> Result<Option<User>>;
     async fn get_user_from_email(email: String) -> Result<Option<User>>;
+    async fn is_username_available(name: String) -> Result<bool>;
+    async fn is_email_available(email: String) -> Result<bool>;
+    async fn create_users(users: Vec<NewUser>) -> Result<Vec<Result<UserId>>>;
+
+    async fn is_email_verified(user_id: UserId) -> Result<bool>;
+
+    async fn get_or_create_user(
+        name: String,
+        email: String,
+        password: String,
+    ) -> Result<(UserId, bool)>;
 
+    // Wiki
     // Page
     async fn get_page_contents(wiki_id: WikiId, slug: String) -> Result<Option<String>>;
 
+    async fn get_page_contents_if_changed(
+        wiki_id: WikiId,
+        slug: String,
+        known_tag: Option<u64>,
+    ) -> Result<PageContentsResult>;
+
+    // Administration
+    async fn set_read_only(
+        admin_session_id: SessionId,
+        admin_user_id: UserId,
+        read_only: bool,
+    ) -> Result<()>;
+
+    async fn logout_sessions(
+        admin_session_id: SessionId,
+        admin_user_id: UserId,
+        sessions: Vec<(SessionId, UserId)>,
+    ) -> Result<Vec<Result<()>>>;
+
+    async fn set_log_level(
+        admin_session_id: SessionId,
+        admin_user_id: UserId,
+        level: String,
+    ) -> Result<()>;
+
     // TODO
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn server_error_round_trip() {
+        let kinds = [
+            ServerError::RequestTooLarge,
+            ServerError::ServerBusy,
+            ServerError::ReadOnly,
+            ServerError::ProtocolTooOld,
+            ServerError::MethodDisabled,
+            ServerError::RateLimited,
+            ServerError::NotAdmin,
+            ServerError::ShuttingDown,
+            ServerError::DeadlinePassed,
+        ];
+
+        for &kind in &kinds {
+            let error = kind.to_sendable();
+
+            assert_eq!(error.name(), kind.name());
+            assert_eq!(error.message(), kind.message());
+            assert_eq!(ServerError::from_sendable(&error), Some(kind));
+        }
+    }
+
+    #[test]
+    fn other_errors() {
+        let error = json!({
+            "name": "user-not-found",
+            "message": "the given user was not found",
+        });
+        let error: SendableError = serde_json::from_value(error).unwrap();
+
+        assert_eq!(ServerError::from_sendable(&error), None);
+    }
+}
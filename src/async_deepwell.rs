@@ -21,13 +21,17 @@
 //! Helper struct to keep `deepwell::Server` in a fixed memory position,
 //! and use `Send + Sync` future channels to communicate with it.
 
-use crate::StdResult;
+use crate::api::{NewUser, PageContentsResult};
+use crate::{Result, StdResult};
 use deepwell::Error as DeepwellError;
 use deepwell::Server as DeepwellServer;
 use deepwell_core::prelude::*;
 use futures::channel::{mpsc, oneshot};
 use futures::prelude::*;
 use ref_map::*;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use unicode_normalization::UnicodeNormalization;
 
 const QUEUE_SIZE: usize = 64;
 
@@ -42,6 +46,14 @@ macro_rules! send {
     };
 }
 
+/// Owns the `deepwell::Server` and processes requests from the RPC handlers.
+///
+/// There is deliberately only one of these, running a single loop.
+/// `deepwell::Server` is neither `Clone` nor `Sync`, so it cannot be shared
+/// between several worker tasks, and creating one per worker would open a
+/// separate database pool and revision store handle for each. Requests are
+/// therefore processed one at a time, and the parallelism of the RPC server
+/// only affects how many requests can be waiting in the queue.
 #[derive(Debug)]
 pub struct AsyncDeepwell {
     server: DeepwellServer,
@@ -62,6 +74,32 @@ impl AsyncDeepwell {
         mpsc::Sender::clone(&self.send)
     }
 
+    /// Finds a user by name, trying the normalized name if the name as given has no match.
+    async fn find_user_by_name(&self, name: &str) -> DeepwellResult<Option<User>> {
+        if let Some(user) = self.server.get_user_from_name(name).await? {
+            return Ok(Some(user));
+        }
+
+        let normalized = normalize_name(name);
+        if normalized == name {
+            return Ok(None);
+        }
+
+        self.server.get_user_from_name(&normalized).await
+    }
+
+    /// Creates a user, storing the name as given.
+    ///
+    /// DEEPWELL only checks the name as given for uniqueness,
+    /// so names which normalize to the same one are rejected here.
+    async fn create_user(&self, name: &str, email: &str, password: &str) -> DeepwellResult<UserId> {
+        if self.find_user_by_name(name).await?.is_some() {
+            return Err(DeepwellError::UserNameExists);
+        }
+
+        self.server.create_user(name, email, password).await
+    }
+
     pub async fn run(&mut self) {
         use AsyncDeepwellRequest::*;
 
@@ -131,7 +169,7 @@ impl AsyncDeepwell {
                 } => {
                     debug!("Received CreateUser request");
 
-                    let result = self.server.create_user(&name, &email, &password).await;
+                    let result = self.create_user(&name, &email, &password).await;
                     send!(response, result);
                 }
                 EditUser {
@@ -159,7 +197,7 @@ impl AsyncDeepwell {
                 GetUserFromName { name, response } => {
                     debug!("Received GetUserFromName request");
 
-                    let result = self.server.get_user_from_name(&name).await;
+                    let result = self.find_user_by_name(&name).await;
                     send!(response, result);
                 }
                 GetUserFromEmail { email, response } => {
@@ -178,6 +216,133 @@ impl AsyncDeepwell {
                     let result = self.server.get_page_contents(wiki_id, &slug).await;
                     send!(response, result);
                 }
+                IsUsernameAvailable { name, response } => {
+                    debug!("Received IsUsernameAvailable request");
+
+                    let result = self
+                        .find_user_by_name(&name)
+                        .await
+                        .map(|user| user.is_none());
+
+                    send!(response, result);
+                }
+                IsEmailAvailable { email, response } => {
+                    debug!("Received IsEmailAvailable request");
+
+                    let result = self
+                        .server
+                        .get_user_from_email(&email)
+                        .await
+                        .map(|user| user.is_none());
+
+                    send!(response, result);
+                }
+                CreateUsers { users, response } => {
+                    debug!("Received CreateUsers request");
+
+                    let mut results = Vec::with_capacity(users.len());
+
+                    for user in users {
+                        let result = self
+                            .create_user(&user.name, &user.email, &user.password)
+                            .await;
+                        results.push(result.map_err(|e| e.to_sendable()));
+                    }
+
+                    let result = Ok(results);
+
+                    send!(response, result);
+                }
+                LogoutSessions { sessions, response } => {
+                    debug!("Received LogoutSessions request");
+
+                    let mut results = Vec::with_capacity(sessions.len());
+                    for (session_id, user_id) in sessions {
+                        let result = self.server.end_session(session_id, user_id).await;
+                        results.push(result.map_err(|e| e.to_sendable()));
+                    }
+
+                    send!(response, Ok(results));
+                }
+                IsEmailVerified { user_id, response } => {
+                    debug!("Received IsEmailVerified request");
+
+                    let result = match self.server.get_user_from_id(user_id).await {
+                        Ok(Some(user)) => Ok(user.is_verified()),
+                        Ok(None) => Err(DeepwellError::UserNotFound),
+                        Err(error) => Err(error),
+                    };
+
+                    send!(response, result);
+                }
+                GetOrCreateUser {
+                    name,
+                    email,
+                    password,
+                    response,
+                } => {
+                    debug!("Received GetOrCreateUser request");
+
+                    // Create first, since the database rejects a duplicate email
+                    // even if another process added it after a lookup here would have run.
+                    let result = async {
+                        let error = match self.create_user(&name, &email, &password).await {
+                            Ok(user_id) => return Ok((user_id, true)),
+                            Err(error) => error,
+                        };
+
+                        match error {
+                            DeepwellError::UserNameExists | DeepwellError::UserEmailExists => (),
+                            _ => return Err(error),
+                        }
+
+                        // Only return the existing user if it has the same name and password
+                        let session = match self.server.try_login(&email, &password, None).await {
+                            Ok(session) => session,
+                            Err(_) => return Err(error),
+                        };
+
+                        let user_id = session.user_id();
+                        self.server
+                            .end_session(session.session_id(), user_id)
+                            .await?;
+
+                        match self.server.get_user_from_id(user_id).await? {
+                            Some(user) if same_name(user.name(), &name) => Ok((user_id, false)),
+                            _ => Err(error),
+                        }
+                    }
+                    .await;
+
+                    send!(response, result);
+                }
+                GetPageContentsIfChanged {
+                    wiki_id,
+                    slug,
+                    known_tag,
+                    response,
+                } => {
+                    debug!("Received GetPageContentsIfChanged request");
+
+                    let result =
+                        self.server
+                            .get_page_contents(wiki_id, &slug)
+                            .await
+                            .map(|contents| match contents {
+                                Some(contents) => {
+                                    let tag = contents_tag(&contents);
+
+                                    if known_tag == Some(tag) {
+                                        PageContentsResult::NotModified
+                                    } else {
+                                        PageContentsResult::Changed { contents, tag }
+                                    }
+                                }
+                                None => PageContentsResult::Missing,
+                            });
+
+                    send!(response, result);
+                }
             }
         }
 
@@ -185,6 +350,27 @@ impl AsyncDeepwell {
     }
 }
 
+/// Normalizes a username when checking it against existing ones.
+///
+/// Surrounding whitespace is removed and the name is converted to Unicode NFC,
+/// so names which look the same are treated as the same. Case is left as-is,
+/// since DEEPWELL already compares usernames case-insensitively.
+fn normalize_name(name: &str) -> String {
+    name.trim().nfc().collect()
+}
+
+/// Whether two usernames are the same, the way DEEPWELL and `create_user` compare them.
+pub fn same_name(first: &str, second: &str) -> bool {
+    normalize_name(first).to_lowercase() == normalize_name(second).to_lowercase()
+}
+
+/// Identifies page contents, to tell whether they changed since a client last fetched them.
+pub fn contents_tag(contents: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    contents.hash(&mut hasher);
+    hasher.finish()
+}
+
 #[derive(Debug)]
 pub enum AsyncDeepwellRequest {
     Ping {
@@ -243,4 +429,71 @@ pub enum AsyncDeepwellRequest {
         slug: String,
         response: oneshot::Sender<DeepwellResult<Option<String>>>,
     },
+    IsUsernameAvailable {
+        name: String,
+        response: oneshot::Sender<DeepwellResult<bool>>,
+    },
+    IsEmailAvailable {
+        email: String,
+        response: oneshot::Sender<DeepwellResult<bool>>,
+    },
+    CreateUsers {
+        users: Vec<NewUser>,
+        response: oneshot::Sender<DeepwellResult<Vec<Result<UserId>>>>,
+    },
+    LogoutSessions {
+        sessions: Vec<(SessionId, UserId)>,
+        response: oneshot::Sender<DeepwellResult<Vec<Result<()>>>>,
+    },
+    IsEmailVerified {
+        user_id: UserId,
+        response: oneshot::Sender<DeepwellResult<bool>>,
+    },
+    GetOrCreateUser {
+        name: String,
+        email: String,
+        password: String,
+        response: oneshot::Sender<DeepwellResult<(UserId, bool)>>,
+    },
+    GetPageContentsIfChanged {
+        wiki_id: WikiId,
+        slug: String,
+        known_tag: Option<u64>,
+        response: oneshot::Sender<DeepwellResult<PageContentsResult>>,
+    },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn normalize_names() {
+        assert_eq!(normalize_name("alice"), "alice");
+        assert_eq!(normalize_name("  alice\t\n"), "alice");
+        assert_eq!(normalize_name("Alice Smith"), "Alice Smith");
+
+        // Decomposed and precomposed forms are the same name
+        assert_eq!(normalize_name("e\u{301}mile"), "\u{e9}mile");
+        assert_eq!(normalize_name("\u{e9}mile"), "\u{e9}mile");
+        assert_eq!(
+            normalize_name("A\u{30a}ngstr\u{f6}m"),
+            normalize_name("\u{c5}ngstro\u{308}m"),
+        );
+    }
+
+    #[test]
+    fn same_names() {
+        assert!(same_name("alice", "alice"));
+        assert!(same_name("Alice", " alice"));
+        assert!(same_name("e\u{301}mile", "\u{c9}mile"));
+        assert!(!same_name("alice", "bob"));
+    }
+
+    #[test]
+    fn contents_tags() {
+        assert_eq!(contents_tag("Hello"), contents_tag("Hello"));
+        assert_ne!(contents_tag("Hello"), contents_tag("Hello!"));
+        assert_ne!(contents_tag(""), contents_tag(" "));
+    }
 }
@@ -0,0 +1,131 @@
+/*
+ * bucket.rs
+ *
+ * deepwell-rpc - RPC server to provide database management and migrations
+ * Copyright (C) 2019-2020 Ammon Smith
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Token bucket used to limit how often something may happen.
+
+use std::time::Instant;
+
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket which refills at `rate` tokens per second, up to `capacity`.
+    pub fn new(rate: f64, capacity: f64) -> Self {
+        TokenBucket {
+            rate,
+            capacity,
+            tokens: capacity,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Takes a token, returning `false` if there are none left.
+    #[inline]
+    pub fn take(&mut self) -> bool {
+        self.take_at(Instant::now())
+    }
+
+    /// Whether the bucket has refilled to capacity by `now`.
+    #[allow(dead_code)] // only used by the server
+    pub fn is_full_at(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.updated = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+    }
+
+    fn take_at(&mut self, now: Instant) -> bool {
+        self.refill(now);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn exhaustion() {
+        let mut bucket = TokenBucket::new(1.0, 3.0);
+        let now = bucket.updated;
+
+        assert!(bucket.take_at(now));
+        assert!(bucket.take_at(now));
+        assert!(bucket.take_at(now));
+        assert!(!bucket.take_at(now));
+        assert!(!bucket.take_at(now));
+    }
+
+    #[test]
+    fn refill() {
+        let mut bucket = TokenBucket::new(2.0, 2.0);
+        let start = bucket.updated;
+
+        assert!(bucket.take_at(start));
+        assert!(bucket.take_at(start));
+        assert!(!bucket.take_at(start));
+
+        // Half a second at two per second is one token
+        let later = start + Duration::from_millis(500);
+        assert!(bucket.take_at(later));
+        assert!(!bucket.take_at(later));
+    }
+
+    #[test]
+    fn refill_capped() {
+        let mut bucket = TokenBucket::new(10.0, 2.0);
+        let start = bucket.updated;
+
+        assert!(bucket.take_at(start));
+
+        // Idle long enough to refill many times over, but only up to capacity
+        let later = start + Duration::from_secs(60);
+        assert!(bucket.take_at(later));
+        assert!(bucket.take_at(later));
+        assert!(!bucket.take_at(later));
+    }
+
+    #[test]
+    fn full() {
+        let mut bucket = TokenBucket::new(1.0, 2.0);
+        let start = bucket.updated;
+
+        assert!(bucket.is_full_at(start));
+        assert!(bucket.take_at(start));
+        assert!(!bucket.is_full_at(start));
+        assert!(bucket.is_full_at(start + Duration::from_secs(1)));
+    }
+}
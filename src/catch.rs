@@ -0,0 +1,154 @@
+/*
+ * catch.rs
+ *
+ * deepwell-rpc - RPC server to provide database management and migrations
+ * Copyright (C) 2019-2020 Ammon Smith
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Recovery from responses which cannot be serialized.
+//!
+//! A codec error while writing would end the connection, taking any other
+//! requests in flight on it along. Instead, the failed response is replaced
+//! with an error for that request alone.
+
+use bytes::{Bytes, BytesMut};
+use serde::Deserialize;
+use serde_json::json;
+use std::io;
+use std::pin::Pin;
+use tarpc::rpc::Response;
+use tokio_serde::{Deserializer, Serializer};
+
+/// `io::ErrorKind::Other`, as tarpc puts it on the wire.
+const ERROR_KIND_OTHER: u32 = 16;
+
+/// Wraps a server codec, answering with an error when a response fails to serialize.
+#[derive(Debug)]
+pub struct CatchErrors<C>(pub C);
+
+impl<C, T> Serializer<Response<T>> for CatchErrors<C>
+where
+    C: Serializer<Response<T>, Error = io::Error> + Unpin,
+    T: for<'de> Deserialize<'de>,
+{
+    type Error = io::Error;
+
+    fn serialize(self: Pin<&mut Self>, item: &Response<T>) -> io::Result<Bytes> {
+        let mut codec = Pin::new(&mut self.get_mut().0);
+
+        match codec.as_mut().serialize(item) {
+            Ok(bytes) => Ok(bytes),
+            Err(error) => {
+                error!(
+                    "Unable to serialize response to request {}: {}",
+                    item.request_id, error,
+                );
+
+                codec.serialize(&error_response(item.request_id))
+            }
+        }
+    }
+}
+
+impl<C, T> Deserializer<T> for CatchErrors<C>
+where
+    C: Deserializer<T, Error = io::Error> + Unpin,
+{
+    type Error = io::Error;
+
+    #[inline]
+    fn deserialize(self: Pin<&mut Self>, src: &BytesMut) -> io::Result<T> {
+        Pin::new(&mut self.get_mut().0).deserialize(src)
+    }
+}
+
+/// Builds a failed response to the given request.
+///
+/// tarpc doesn't allow constructing responses directly, so this goes through its serde form.
+fn error_response<T>(request_id: u64) -> Response<T>
+where
+    T: for<'de> Deserialize<'de>,
+{
+    let value = json!({
+        "request_id": request_id,
+        "message": {
+            "Err": {
+                "kind": ERROR_KIND_OTHER,
+                "detail": "internal serialization error",
+            },
+        },
+    });
+
+    serde_json::from_value(value).expect("Error response doesn't match tarpc's format")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transport::{JsonTransport, TransportFactory};
+    use serde::ser::{self, Serialize};
+
+    #[derive(Deserialize, Debug)]
+    struct Unserializable;
+
+    impl Serialize for Unserializable {
+        fn serialize<S: ser::Serializer>(&self, _: S) -> Result<S::Ok, S::Error> {
+            Err(ser::Error::custom("cannot serialize"))
+        }
+    }
+
+    fn respond<T>(request_id: u64, message: Option<T>) -> Response<Option<T>>
+    where
+        T: for<'de> Deserialize<'de>,
+    {
+        let value = json!({ "request_id": request_id, "message": { "Ok": null } });
+        let mut response: Response<Option<T>> = serde_json::from_value(value).unwrap();
+        response.message = Ok(message);
+        response
+    }
+
+    fn write<T>(response: &Response<T>) -> io::Result<Response<T>>
+    where
+        T: Serialize + for<'de> Deserialize<'de> + Unpin + Send + 'static,
+    {
+        let codec = TransportFactory::<(), Response<T>>::codec(&JsonTransport);
+        let mut codec = CatchErrors(codec);
+        let bytes = Pin::new(&mut codec).serialize(response)?;
+
+        Ok(serde_json::from_slice(&bytes).unwrap())
+    }
+
+    #[test]
+    fn passthrough() {
+        let response = write(&respond(4, Some(str!("page")))).unwrap();
+
+        assert_eq!(response.request_id, 4);
+        assert_eq!(response.message, Ok(Some(str!("page"))));
+    }
+
+    #[test]
+    fn unserializable() {
+        let response = write(&respond(7, Some(Unserializable))).unwrap();
+        let error = response.message.unwrap_err();
+
+        assert_eq!(response.request_id, 7);
+        assert_eq!(error.kind, io::ErrorKind::Other);
+        assert_eq!(
+            error.detail.as_deref(),
+            Some("internal serialization error")
+        );
+    }
+}
@@ -18,101 +18,569 @@
  * along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
 
-use crate::api::{DeepwellClient, PROTOCOL_VERSION};
+use crate::api::{
+    BatchLimits, DeepwellClient, DeepwellRequest, DeepwellResponse, EventBatch, NewUser,
+    PageContentsResult, ReadinessInfo, SessionInvalidated, UptimeInfo, PROTOCOL_VERSION,
+};
+use crate::bucket::TokenBucket;
+use crate::stats::ClientStats;
+use crate::transport::{JsonTransport, TransportFactory};
 use crate::Result;
 use deepwell_core::prelude::*;
+use futures::future::{BoxFuture, Future, FutureExt};
+use futures::stream::{self, Stream};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Debug};
+use std::io;
 use std::net::SocketAddr;
-use std::time::Duration;
-use std::{io, mem};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 use tarpc::rpc::client::Config as RpcConfig;
-use tarpc::rpc::context;
+use tarpc::rpc::context::{self, Context};
+use tarpc::rpc::{ClientMessage, Response};
 use tarpc::serde_transport::tcp;
-use tokio::time::timeout;
-use tokio_serde::formats::Json;
+use tarpc::trace::TraceId;
+use tokio::net::TcpStream;
+use tokio::time::{delay_for, timeout};
+
+// Default clock offset beyond which session expiry calculations become unreliable
+const DEFAULT_MAX_CLOCK_SKEW: Duration = Duration::from_secs(5);
+
+// How long an event stream waits before polling again after an error
+const EVENT_POLL_RETRY: Duration = Duration::from_secs(1);
+
+// How many times a call is tried before giving up
+const MAX_ATTEMPTS: u8 = 5;
 
 macro_rules! ctx {
-    () => {
-        context::current()
-    };
+    ($self:expr) => {{
+        // Let the server know when we stop waiting
+        let mut ctx = context::current();
+        ctx.deadline = wire_deadline($self.deadline);
+
+        // Use the caller's trace ID, if any, so calls can be found in the server logs
+        if let Some(trace_id) = $self.trace_id {
+            ctx.trace_context.trace_id = trace_id;
+        }
+
+        ctx
+    }};
 }
 
 macro_rules! retry {
-    ($self:expr, $new_future:expr,) => {
-        retry!($self, $new_future);
+    ($self:expr, $method:expr, $new_future:expr,) => {
+        retry!($self, $method, $new_future);
     };
 
-    ($self:expr, $new_future:expr) => {{
+    ($self:expr, $method:expr, $new_future:expr) => {{
         use io::{Error, ErrorKind};
 
+        let start = Instant::now();
+        let call_timeout = $self.timeout_for($method);
+        $self.stats.record_call($method);
+        $self.last_call_retried = false;
+        $self.last_call_reconnected = false;
+
         // Where to store the results while looping each retry
         // Default is `None`, or 'never got answer'
         let mut result = None;
 
-        for _ in 0..5u8 {
+        for attempt in 0..MAX_ATTEMPTS {
+            if attempt > 0 {
+                $self.stats.record_retry($method);
+                $self.last_call_retried = true;
+            }
+
+            // The server changed protocol, its responses can't be trusted
+            if let Err(error) = $self.check_protocol() {
+                result = Some(Err(error));
+                break;
+            }
+
+            $self.deadline = SystemTime::now() + call_timeout;
             let fut = $new_future;
 
-            match timeout($self.timeout, fut).await {
+            let error = match timeout(call_timeout, fut).await {
+                // The connection was dropped, such as after a malformed frame
+                Ok(Err(error)) if $self.auto_reconnect && connection_lost(&error) => {
+                    warn!("Lost connection to remote server: {}", error);
+
+                    error
+                }
                 Ok(resp) => {
-                    result = Some(resp?);
+                    result = Some(resp);
                     break;
                 }
                 Err(_) => {
                     warn!(
                         "Remote call timed out ({:.3} seconds)",
-                        $self.timeout.as_secs_f64(),
+                        call_timeout.as_secs_f64(),
                     );
 
-                    // Attempt to reconnect
-                    if let Err(error) = $self.reconnect().await {
-                        warn!("Failed to reconnect to remote server");
+                    let error =
+                        Error::new(ErrorKind::TimedOut, "Remote server not responding in time");
 
-                        return Err(error);
+                    // Leave reconnection to the caller
+                    if !$self.auto_reconnect {
+                        result = Some(Err(error));
+                        break;
                     }
+
+                    error
                 }
+            };
+
+            // Don't reconnect or use up the budget if there won't be another try
+            if attempt + 1 == MAX_ATTEMPTS {
+                result = Some(Err(error));
+                break;
+            }
+
+            // Fail fast if many calls have been retrying recently
+            if let Some(budget) = $self.retry_budget.as_ref() {
+                if !budget.lock().expect("Retry budget lock poisoned").take() {
+                    warn!("Retry budget exhausted, not retrying");
+
+                    result = Some(Err(error));
+                    break;
+                }
+            }
+
+            // Attempt to reconnect
+            if let Err(error) = $self.reconnect().await {
+                warn!("Failed to reconnect to remote server");
+
+                result = Some(Err(error));
+                break;
             }
         }
 
-        result
-            .ok_or_else(|| Error::new(ErrorKind::TimedOut, "Remote server not responding in time"))
+        $self.stats.record_time($method, start.elapsed());
+
+        result.unwrap_or_else(|| {
+            Err(Error::new(
+                ErrorKind::TimedOut,
+                "Remote server not responding in time",
+            ))
+        })
     }};
 }
 
+type Connection = (DeepwellClient, SocketAddr, SocketAddr);
+
+/// Results of `Client::warm_up()`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct WarmUpInfo {
+    /// Round-trip time of a ping to the server.
+    pub latency: Duration,
+
+    /// How far the server's clock is from the local one, as in `clock_skew()`.
+    pub clock_skew: ClockSkew,
+}
+
+/// How far the server's clock is from the local one.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct ClockSkew {
+    /// The size of the difference.
+    pub offset: Duration,
+
+    /// Whether the server's clock is ahead of the local one, rather than behind.
+    pub server_ahead: bool,
+}
+
+impl ClockSkew {
+    fn from_secs_f64(seconds: f64) -> Self {
+        ClockSkew {
+            offset: Duration::from_secs_f64(seconds.abs()),
+            server_ahead: seconds > 0.0,
+        }
+    }
+
+    /// The difference in seconds, positive if the server is ahead and negative if it is behind.
+    pub fn as_secs_f64(&self) -> f64 {
+        let seconds = self.offset.as_secs_f64();
+
+        if self.server_ahead {
+            seconds
+        } else {
+            -seconds
+        }
+    }
+}
+
+type OpenFn = dyn Fn(SocketAddr, bool) -> BoxFuture<'static, io::Result<Connection>> + Send + Sync;
+
+/// Opens connections using the transport given to the builder.
+#[derive(Clone)]
+struct Connector {
+    open: Arc<OpenFn>,
+    tcp_nodelay: bool,
+}
+
+impl Connector {
+    fn new<T>(transport: T, tcp_nodelay: bool) -> Self
+    where
+        T: TransportFactory<Response<DeepwellResponse>, ClientMessage<DeepwellRequest>>,
+    {
+        let transport = Arc::new(transport);
+
+        let open = move |address: SocketAddr, tcp_nodelay: bool| {
+            let transport = Arc::clone(&transport);
+
+            async move {
+                let stream = open_stream(address, tcp_nodelay).await?;
+                let transport = tcp::new(stream, transport.codec());
+
+                let peer_addr = transport.peer_addr()?;
+                let local_addr = transport.local_addr()?;
+
+                let config = RpcConfig::default();
+                let client = DeepwellClient::new(config, transport).spawn()?;
+
+                Ok((client, peer_addr, local_addr))
+            }
+            .boxed()
+        };
+
+        Connector {
+            open: Arc::new(open),
+            tcp_nodelay,
+        }
+    }
+
+    #[inline]
+    fn connect(&self, address: SocketAddr) -> BoxFuture<'static, io::Result<Connection>> {
+        (self.open)(address, self.tcp_nodelay)
+    }
+}
+
+/// Opens the socket for a connection, before it's framed.
+async fn open_stream(address: SocketAddr, tcp_nodelay: bool) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect(address).await?;
+    stream.set_nodelay(tcp_nodelay)?;
+
+    Ok(stream)
+}
+
+impl Debug for Connector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Connector")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientBuilder {
+    address: SocketAddr,
+    connector: Connector,
+    timeout: Duration,
+    method_timeouts: HashMap<&'static str, Duration>,
+    auto_reconnect: bool,
+    retry_budget: Option<Arc<Mutex<TokenBucket>>>,
+    warm_up: bool,
+    max_clock_skew: Duration,
+}
+
+impl ClientBuilder {
+    #[inline]
+    pub fn new(address: SocketAddr, timeout: Duration) -> Self {
+        ClientBuilder {
+            address,
+            connector: Connector::new(JsonTransport, true),
+            timeout,
+            method_timeouts: HashMap::new(),
+            auto_reconnect: true,
+            retry_budget: None,
+            warm_up: false,
+            max_clock_skew: DEFAULT_MAX_CLOCK_SKEW,
+        }
+    }
+
+    /// Overrides the timeout for calls to a particular method, such as `"get_users_from_ids"`.
+    /// Methods without an override use the timeout given to `new()`.
+    #[inline]
+    pub fn method_timeout(mut self, method: &'static str, timeout: Duration) -> Self {
+        self.method_timeouts.insert(method, timeout);
+        self
+    }
+
+    /// Whether to reconnect and retry when a call times out or its connection is lost.
+    /// Defaults to `true`.
+    ///
+    /// If disabled, the call fails at once without reconnecting. A lost connection
+    /// returns the transport's error, usually `ConnectionReset`, and a timeout returns
+    /// a `TimedOut` error, as there is no transport error to report in that case.
+    #[inline]
+    pub fn auto_reconnect(mut self, value: bool) -> Self {
+        self.auto_reconnect = value;
+        self
+    }
+
+    /// Limits retries across all calls to `rate` per second, allowing bursts of up to `burst`.
+    /// Once used up, timed out or disconnected calls fail immediately instead of retrying.
+    /// By default there is no limit.
+    ///
+    /// The budget is shared by every client connected from this builder or its clones,
+    /// including the extra connections event streams open.
+    #[inline]
+    pub fn retry_budget(mut self, rate: f64, burst: u32) -> Self {
+        let bucket = TokenBucket::new(rate, f64::from(burst));
+        self.retry_budget = Some(Arc::new(Mutex::new(bucket)));
+        self
+    }
+
+    /// Whether to call `warm_up()` after connecting and reconnecting, so a server
+    /// which accepts connections but cannot serve requests fails right away.
+    /// Defaults to `false`.
+    #[inline]
+    pub fn warm_up(mut self, value: bool) -> Self {
+        self.warm_up = value;
+        self
+    }
+
+    /// How far the server's clock may be from the local one before a warning
+    /// is logged. The clock is checked on each connection, and the result is
+    /// available from `Client::connect_clock_skew()`. Defaults to 5 seconds.
+    #[inline]
+    pub fn max_clock_skew(mut self, max_skew: Duration) -> Self {
+        self.max_clock_skew = max_skew;
+        self
+    }
+
+    /// Whether to disable Nagle's algorithm on the connection, so small requests
+    /// are sent without delay. Defaults to `true`.
+    #[inline]
+    pub fn tcp_nodelay(mut self, value: bool) -> Self {
+        self.connector.tcp_nodelay = value;
+        self
+    }
+
+    /// Sets the codec used to frame messages. Defaults to `JsonTransport`.
+    /// The server must be using the same one.
+    pub fn transport<T>(mut self, transport: T) -> Self
+    where
+        T: TransportFactory<Response<DeepwellResponse>, ClientMessage<DeepwellRequest>>,
+    {
+        self.connector = Connector::new(transport, self.connector.tcp_nodelay);
+        self
+    }
+
+    pub async fn connect(self) -> io::Result<Client> {
+        let ClientBuilder {
+            address,
+            connector,
+            timeout,
+            method_timeouts,
+            auto_reconnect,
+            retry_budget,
+            warm_up,
+            max_clock_skew,
+        } = self;
+
+        let connection = connector.connect(address).await?;
+
+        let mut client = Client {
+            client: connection.0.clone(),
+            address,
+            connector,
+            peer_addr: connection.1,
+            local_addr: connection.2,
+            timeout,
+            method_timeouts,
+            deadline: SystemTime::now(),
+            trace_id: None,
+            auto_reconnect,
+            retry_budget,
+            warm_up,
+            max_clock_skew,
+            connect_clock_skew: ClockSkew::default(),
+            stats: ClientStats::default(),
+            server_protocol: String::new(),
+            protocol_mismatch: None,
+            last_call_retried: false,
+            last_call_reconnected: false,
+        };
+
+        let (connection, version) = client.negotiate(connection).await?;
+        client.set_connection(connection);
+        client.set_server_protocol(version);
+        client.check_clock().await?;
+
+        if client.warm_up {
+            client.warm_up().await?;
+        }
+
+        Ok(client)
+    }
+}
+
 #[derive(Debug)]
 pub struct Client {
     client: DeepwellClient,
     address: SocketAddr,
+    connector: Connector,
+    peer_addr: SocketAddr,
+    local_addr: SocketAddr,
     timeout: Duration,
+    method_timeouts: HashMap<&'static str, Duration>,
+    deadline: SystemTime, // of the call in progress, set by retry!()
+    trace_id: Option<TraceId>,
+    auto_reconnect: bool,
+    retry_budget: Option<Arc<Mutex<TokenBucket>>>,
+    warm_up: bool,
+    max_clock_skew: Duration,
+    connect_clock_skew: ClockSkew,
+    stats: ClientStats,
+    server_protocol: String,
+    protocol_mismatch: Option<String>, // server version, if it changed on reconnecting
+    last_call_retried: bool,
+    last_call_reconnected: bool,
 }
 
 impl Client {
+    #[inline]
     pub async fn new(address: SocketAddr, timeout: Duration) -> io::Result<Self> {
-        let transport = tcp::connect(&address, Json::default()).await?;
-        let config = RpcConfig::default();
-        let client = DeepwellClient::new(config, transport).spawn()?;
-
-        Ok(Client {
-            client,
-            address,
-            timeout,
-        })
+        ClientBuilder::new(address, timeout).connect().await
     }
 
     async fn reconnect(&mut self) -> io::Result<()> {
         debug!("Attempting to reconnect to source...");
-        let mut client = Self::new(self.address, self.timeout).await?;
+        let connection = self.connector.connect(self.address).await?;
+        let (connection, version) = self.negotiate(connection).await?;
+
+        // The server may have been replaced by one speaking a different protocol.
+        // Keep the old connection, and refuse all further calls rather than use it.
+        if version != self.server_protocol {
+            let error = protocol_mismatch_error(&self.server_protocol, &version);
+            self.protocol_mismatch = Some(version);
+            return Err(error);
+        }
+
+        self.set_connection(connection);
+        self.stats.record_reconnect();
+        self.last_call_reconnected = true;
+        self.check_clock().await?;
+
+        if self.warm_up {
+            self.warm_up().await?;
+        }
 
         debug!("Successfully reconnected");
-        mem::swap(self, &mut client);
+        Ok(())
+    }
 
+    fn set_connection(&mut self, (client, peer_addr, local_addr): Connection) {
+        self.client = client;
+        self.peer_addr = peer_addr;
+        self.local_addr = local_addr;
+    }
+
+    /// Finds the server's protocol version for a new connection, without retrying.
+    /// Returns the connection to use, which is a different one if the server is
+    /// too old to have `handshake()`.
+    async fn negotiate(&self, connection: Connection) -> io::Result<(Connection, String)> {
+        let error = match self.handshake(&connection.0).await {
+            Ok(version) => return Ok((connection, version)),
+            Err(error) => error,
+        };
+
+        match error.kind() {
+            io::ErrorKind::ConnectionRefused | io::ErrorKind::TimedOut => return Err(error),
+            _ => (),
+        }
+
+        // Older servers close the connection on methods they don't know
+        debug!("Handshake failed ({}), falling back to protocol()", error);
+
+        let connection = self.connector.connect(self.address).await?;
+        let call_timeout = self.timeout_for("protocol");
+        let ctx = single_context(call_timeout);
+        let mut client = connection.0.clone();
+        let version = call_once(call_timeout, client.protocol(ctx)).await?;
+
+        Ok((connection, version))
+    }
+
+    /// Exchanges protocol versions with the server over the given connection,
+    /// without retrying or reconnecting. Returns the server's version.
+    /// Fails if the server refuses this client's version.
+    async fn handshake(&self, client: &DeepwellClient) -> io::Result<String> {
+        let call_timeout = self.timeout_for("handshake");
+        let ctx = single_context(call_timeout);
+        let mut client = client.clone();
+        let call = client.handshake(ctx, PROTOCOL_VERSION.to_string());
+
+        let version = match call_once(call_timeout, call).await? {
+            Ok(version) => version,
+            Err(error) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    format!("Server refused handshake: {:?}", error),
+                ))
+            }
+        };
+
+        Ok(version)
+    }
+
+    /// Fails if the server changed protocol versions while reconnecting.
+    fn check_protocol(&self) -> io::Result<()> {
+        match self.protocol_mismatch.as_ref() {
+            Some(version) => Err(protocol_mismatch_error(&self.server_protocol, version)),
+            None => Ok(()),
+        }
+    }
+
+    /// Reads the server's clock on the current connection, without retrying or reconnecting.
+    async fn check_clock(&mut self) -> io::Result<()> {
+        let call_timeout = self.timeout_for("time");
+        let ctx = single_context(call_timeout);
+        let before = unix_time(SystemTime::now());
+        let server_time = call_once(call_timeout, self.client.time(ctx)).await?;
+        let after = unix_time(SystemTime::now());
+
+        self.connect_clock_skew = check_skew(server_time, before, after, self.max_clock_skew);
         Ok(())
     }
 
-    // Misc
-    pub async fn protocol(&mut self) -> io::Result<String> {
-        info!("Method: protocol");
+    /// Pings the server and reads its clock, without retrying or reconnecting.
+    /// Fails if the server accepts the connection but cannot serve requests.
+    pub async fn warm_up(&mut self) -> io::Result<WarmUpInfo> {
+        self.check_protocol()?;
+
+        let call_timeout = self.timeout_for("ping");
+        let ctx = single_context(call_timeout);
+        let started = Instant::now();
+
+        if let Err(error) = call_once(call_timeout, self.client.ping(ctx)).await? {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Server unable to handle requests: {:?}", error),
+            ));
+        }
+
+        let latency = started.elapsed();
+
+        let call_timeout = self.timeout_for("time");
+        let ctx = single_context(call_timeout);
+        let before = unix_time(SystemTime::now());
+        let server_time = call_once(call_timeout, self.client.time(ctx)).await?;
+        let after = unix_time(SystemTime::now());
+
+        let clock_skew = check_skew(server_time, before, after, self.max_clock_skew);
 
-        let version = retry!(self, self.client.protocol(ctx!()))?;
+        debug!(
+            "Warmed up connection, latency {:?}, clock skew {:.3} seconds",
+            latency,
+            clock_skew.as_secs_f64(),
+        );
 
+        Ok(WarmUpInfo {
+            latency,
+            clock_skew,
+        })
+    }
+
+    fn set_server_protocol(&mut self, version: String) {
         if PROTOCOL_VERSION != version {
             warn!(
                 "Protocol version mismatch! Client: {}, server: {}",
@@ -120,19 +588,165 @@ impl Client {
             );
         }
 
+        self.server_protocol = version;
+    }
+
+    /// Opens a separate connection with the same settings as this one.
+    async fn duplicate(&self) -> io::Result<Self> {
+        ClientBuilder {
+            address: self.address,
+            connector: self.connector.clone(),
+            timeout: self.timeout,
+            method_timeouts: self.method_timeouts.clone(),
+            auto_reconnect: self.auto_reconnect,
+            retry_budget: self.retry_budget.clone(),
+            warm_up: self.warm_up,
+            max_clock_skew: self.max_clock_skew,
+        }
+        .connect()
+        .await
+    }
+
+    fn timeout_for(&self, method: &str) -> Duration {
+        self.method_timeouts
+            .get(method)
+            .copied()
+            .unwrap_or(self.timeout)
+    }
+
+    /// Returns the address of the server for the current connection.
+    #[inline]
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    /// Returns the local address of the current connection.
+    #[inline]
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Returns the server's protocol version, as of the last connection or call to `protocol()`.
+    #[inline]
+    pub fn server_protocol(&self) -> &str {
+        &self.server_protocol
+    }
+
+    /// Returns how far the server's clock was from the local one,
+    /// as measured during the last connection. See `clock_skew()`.
+    #[inline]
+    pub fn connect_clock_skew(&self) -> ClockSkew {
+        self.connect_clock_skew
+    }
+
+    /// Whether the client has stopped making calls, because the server changed
+    /// to a different protocol version while reconnecting.
+    /// A new client must be created to continue.
+    #[inline]
+    pub fn protocol_mismatch(&self) -> bool {
+        self.protocol_mismatch.is_some()
+    }
+
+    /// Whether the most recent call had to be retried, after timing out or losing the connection.
+    #[inline]
+    pub fn last_call_retried(&self) -> bool {
+        self.last_call_retried
+    }
+
+    /// Whether the most recent call reconnected to the server.
+    #[inline]
+    pub fn last_call_reconnected(&self) -> bool {
+        self.last_call_reconnected
+    }
+
+    /// Sets the trace ID sent with each following call, which the server includes
+    /// in its log lines for the call. If unset, each call gets a new random one.
+    #[inline]
+    pub fn set_trace_id(&mut self, trace_id: Option<TraceId>) {
+        self.trace_id = trace_id;
+    }
+
+    #[inline]
+    pub fn trace_id(&self) -> Option<TraceId> {
+        self.trace_id
+    }
+
+    /// Makes a call using the given context, instead of one built from this client's
+    /// timeouts and trace ID. The call is given up at the context's deadline, and is
+    /// not retried. The server sees the deadline rounded up to a whole second.
+    ///
+    /// For example:
+    /// `client.call_with_context(ctx, |mut raw, ctx| async move { raw.ping(ctx).await })`
+    pub async fn call_with_context<F, Fut, T>(&mut self, mut ctx: Context, call: F) -> io::Result<T>
+    where
+        F: FnOnce(DeepwellClient, Context) -> Fut,
+        Fut: Future<Output = io::Result<T>>,
+    {
+        let call_timeout = ctx
+            .deadline
+            .duration_since(SystemTime::now())
+            .unwrap_or_default();
+
+        ctx.deadline = wire_deadline(ctx.deadline);
+        self.check_protocol()?;
+        call_once(call_timeout, call(self.client.clone(), ctx)).await
+    }
+
+    /// Returns the counters for calls made by this client.
+    #[inline]
+    pub fn stats(&self) -> &ClientStats {
+        &self.stats
+    }
+
+    // Misc
+    pub async fn protocol(&mut self) -> io::Result<String> {
+        info!("Method: protocol");
+
+        let version = retry!(self, "protocol", self.client.protocol(ctx!(self)))?;
+        self.set_server_protocol(version.clone());
+
         Ok(version)
     }
 
     pub async fn ping(&mut self) -> io::Result<Result<()>> {
         info!("Method: ping");
 
-        retry!(self, self.client.ping(ctx!()))
+        retry!(self, "ping", self.client.ping(ctx!(self)))
     }
 
     pub async fn time(&mut self) -> io::Result<f64> {
         info!("Method: time");
 
-        retry!(self, self.client.time(ctx!()))
+        retry!(self, "time", self.client.time(ctx!(self)))
+    }
+
+    pub async fn uptime(&mut self) -> io::Result<Result<UptimeInfo>> {
+        info!("Method: uptime");
+
+        retry!(self, "uptime", self.client.uptime(ctx!(self)))
+    }
+
+    pub async fn readiness(&mut self) -> io::Result<ReadinessInfo> {
+        info!("Method: readiness");
+
+        retry!(self, "readiness", self.client.readiness(ctx!(self)))
+    }
+
+    pub async fn batch_limits(&mut self) -> io::Result<Result<BatchLimits>> {
+        info!("Method: batch_limits");
+
+        retry!(self, "batch_limits", self.client.batch_limits(ctx!(self)))
+    }
+
+    /// Estimates how far the server's clock is from the local one.
+    ///
+    /// The local time is taken at the midpoint of the call to account for latency.
+    pub async fn clock_skew(&mut self) -> io::Result<ClockSkew> {
+        let before = unix_time(SystemTime::now());
+        let server_time = self.time().await?;
+        let after = unix_time(SystemTime::now());
+
+        Ok(check_skew(server_time, before, after, self.max_clock_skew))
     }
 
     // Session
@@ -146,8 +760,9 @@ impl Client {
 
         retry!(
             self,
+            "login",
             self.client.login(
-                ctx!(),
+                ctx!(self),
                 username_or_email.clone(),
                 password.clone(),
                 remote_address.clone(),
@@ -162,7 +777,11 @@ impl Client {
     ) -> io::Result<Result<()>> {
         info!("Method: logout");
 
-        retry!(self, self.client.logout(ctx!(), session_id, user_id))
+        retry!(
+            self,
+            "logout",
+            self.client.logout(ctx!(self), session_id, user_id)
+        )
     }
 
     pub async fn logout_others(
@@ -172,7 +791,11 @@ impl Client {
     ) -> io::Result<Result<Vec<Session>>> {
         info!("Method logout_others");
 
-        retry!(self, self.client.logout_others(ctx!(), session_id, user_id))
+        retry!(
+            self,
+            "logout_others",
+            self.client.logout_others(ctx!(self), session_id, user_id)
+        )
     }
 
     pub async fn check_session(
@@ -182,7 +805,99 @@ impl Client {
     ) -> io::Result<Result<()>> {
         info!("Method: session");
 
-        retry!(self, self.client.check_session(ctx!(), session_id, user_id))
+        retry!(
+            self,
+            "check_session",
+            self.client.check_session(ctx!(self), session_id, user_id)
+        )
+    }
+
+    /// Runs calls which need a session, logging in again if it has expired.
+    ///
+    /// If `f` returns an error and the session is then found to be invalid,
+    /// `credentials` is called to get the username or email and password,
+    /// the new session is stored in `session`, and `f` is run once more.
+    /// This way the password need not be kept around between calls.
+    pub async fn with_session<C, F, T>(
+        &mut self,
+        session: &mut Session,
+        mut credentials: C,
+        mut f: F,
+    ) -> io::Result<Result<T>>
+    where
+        C: FnMut() -> (String, String),
+        F: for<'a> FnMut(&'a mut Client, &'a Session) -> BoxFuture<'a, io::Result<Result<T>>>,
+    {
+        let error = match f(self, session).await? {
+            Ok(value) => return Ok(Ok(value)),
+            Err(error) => error,
+        };
+
+        // Only log in again if the failure was from the session
+        if let Ok(()) = self
+            .check_session(session.session_id(), session.user_id())
+            .await?
+        {
+            return Ok(Err(error));
+        }
+
+        debug!("Session expired, logging in again");
+
+        let (username_or_email, password) = credentials();
+        *session = match self.login(username_or_email, password, None).await? {
+            Ok(new_session) => new_session,
+            Err(error) => return Ok(Err(error)),
+        };
+
+        f(self, session).await
+    }
+
+    /// Waits for the user's sessions to be ended, returning the events and the cursor
+    /// for the next call.
+    /// With no cursor, returns immediately with the current cursor and no events.
+    ///
+    /// The server answers shortly before the call's timeout if nothing happens,
+    /// so consider raising it with `ClientBuilder::method_timeout()`.
+    pub async fn poll_session_events(
+        &mut self,
+        session_id: SessionId,
+        user_id: UserId,
+        cursor: Option<u64>,
+    ) -> io::Result<Result<EventBatch<SessionInvalidated>>> {
+        info!("Method: poll_session_events");
+
+        retry!(
+            self,
+            "poll_session_events",
+            self.client
+                .poll_session_events(ctx!(self), session_id, user_id, cursor)
+        )
+    }
+
+    /// Subscribes to the user's sessions being ended, such as by logging out.
+    ///
+    /// This opens a separate connection for long polling, so calls on this
+    /// client are not held up. Only events after the subscription are delivered.
+    /// If the session stops being valid, the stream yields a `PermissionDenied` error and ends.
+    pub async fn session_events(
+        &self,
+        session_id: SessionId,
+        user_id: UserId,
+    ) -> io::Result<Result<impl Stream<Item = io::Result<SessionInvalidated>>>> {
+        let mut client = self.duplicate().await?;
+        let cursor = match client
+            .poll_session_events(session_id, user_id, None)
+            .await?
+        {
+            Ok(EventBatch { cursor, .. }) => cursor,
+            Err(error) => return Ok(Err(error)),
+        };
+
+        Ok(Ok(event_stream(client, cursor, move |client, cursor| {
+            client
+                .poll_session_events(session_id, user_id, Some(cursor))
+                .boxed()
+        })))
     }
 
     // User
@@ -196,8 +911,9 @@ impl Client {
 
         retry!(
             self,
+            "create_user",
             self.client
-                .create_user(ctx!(), name.clone(), email.clone(), password.clone()),
+                .create_user(ctx!(self), name.clone(), email.clone(), password.clone()),
         )
     }
 
@@ -210,14 +926,19 @@ impl Client {
 
         retry!(
             self,
-            self.client.edit_user(ctx!(), user_id, changes.clone()),
+            "edit_user",
+            self.client.edit_user(ctx!(self), user_id, changes.clone()),
         )
     }
 
     pub async fn get_user_from_id(&mut self, user_id: UserId) -> io::Result<Result<Option<User>>> {
         info!("Method: get_user_from_id");
 
-        retry!(self, self.client.get_user_from_id(ctx!(), user_id))
+        retry!(
+            self,
+            "get_user_from_id",
+            self.client.get_user_from_id(ctx!(self), user_id)
+        )
     }
 
     pub async fn get_users_from_ids(
@@ -228,22 +949,96 @@ impl Client {
 
         retry!(
             self,
-            self.client.get_users_from_ids(ctx!(), user_ids.clone()),
+            "get_users_from_ids",
+            self.client.get_users_from_ids(ctx!(self), user_ids.clone()),
         )
     }
 
     pub async fn get_user_from_name(&mut self, name: String) -> io::Result<Result<Option<User>>> {
         info!("Method: get_user_from_name");
 
-        retry!(self, self.client.get_user_from_name(ctx!(), name.clone()))
+        retry!(
+            self,
+            "get_user_from_name",
+            self.client.get_user_from_name(ctx!(self), name.clone())
+        )
     }
 
     pub async fn get_user_from_email(&mut self, email: String) -> io::Result<Result<Option<User>>> {
         info!("Method: get_user_from_email");
 
-        retry!(self, self.client.get_user_from_email(ctx!(), email.clone()))
+        retry!(
+            self,
+            "get_user_from_email",
+            self.client.get_user_from_email(ctx!(self), email.clone())
+        )
+    }
+
+    pub async fn is_username_available(&mut self, name: String) -> io::Result<Result<bool>> {
+        info!("Method: is_username_available");
+
+        retry!(
+            self,
+            "is_username_available",
+            self.client.is_username_available(ctx!(self), name.clone())
+        )
     }
 
+    pub async fn is_email_available(&mut self, email: String) -> io::Result<Result<bool>> {
+        info!("Method: is_email_available");
+
+        retry!(
+            self,
+            "is_email_available",
+            self.client.is_email_available(ctx!(self), email.clone())
+        )
+    }
+
+    pub async fn create_users(
+        &mut self,
+        users: Vec<NewUser>,
+    ) -> io::Result<Result<Vec<Result<UserId>>>> {
+        info!("Method: create_users");
+
+        retry!(
+            self,
+            "create_users",
+            self.client.create_users(ctx!(self), users.clone())
+        )
+    }
+
+    pub async fn is_email_verified(&mut self, user_id: UserId) -> io::Result<Result<bool>> {
+        info!("Method: is_email_verified");
+
+        retry!(
+            self,
+            "is_email_verified",
+            self.client.is_email_verified(ctx!(self), user_id)
+        )
+    }
+
+    pub async fn get_or_create_user(
+        &mut self,
+        name: String,
+        email: String,
+        password: String,
+    ) -> io::Result<Result<(UserId, bool)>> {
+        info!("Method: get_or_create_user");
+
+        retry!(
+            self,
+            "get_or_create_user",
+            self.client.get_or_create_user(
+                ctx!(self),
+                name.clone(),
+                email.clone(),
+                password.clone()
+            )
+        )
+    }
+
+    // Wiki
+    // Page
     pub async fn get_page_contents(
         &mut self,
         wiki_id: WikiId,
@@ -252,10 +1047,937 @@ impl Client {
         info!("Method: get_page_contenst");
         retry!(
             self,
+            "get_page_contents",
+            self.client
+                .get_page_contents(ctx!(self), wiki_id.clone(), slug.clone())
+        )
+    }
+
+    pub async fn get_page_contents_if_changed(
+        &mut self,
+        wiki_id: WikiId,
+        slug: String,
+        known_tag: Option<u64>,
+    ) -> io::Result<Result<PageContentsResult>> {
+        info!("Method: get_page_contents_if_changed");
+
+        retry!(
+            self,
+            "get_page_contents_if_changed",
+            self.client
+                .get_page_contents_if_changed(ctx!(self), wiki_id, slug.clone(), known_tag)
+        )
+    }
+
+    // Administration
+    pub async fn set_read_only(
+        &mut self,
+        admin_session_id: SessionId,
+        admin_user_id: UserId,
+        read_only: bool,
+    ) -> io::Result<Result<()>> {
+        info!("Method: set_read_only");
+
+        retry!(
+            self,
+            "set_read_only",
             self.client
-                .get_page_contents(ctx!(), wiki_id.clone(), slug.clone())
+                .set_read_only(ctx!(self), admin_session_id, admin_user_id, read_only)
+        )
+    }
+
+    pub async fn logout_sessions(
+        &mut self,
+        admin_session_id: SessionId,
+        admin_user_id: UserId,
+        sessions: Vec<(SessionId, UserId)>,
+    ) -> io::Result<Result<Vec<Result<()>>>> {
+        info!("Method: logout_sessions");
+
+        retry!(
+            self,
+            "logout_sessions",
+            self.client.logout_sessions(
+                ctx!(self),
+                admin_session_id,
+                admin_user_id,
+                sessions.clone()
+            )
+        )
+    }
+
+    pub async fn set_log_level(
+        &mut self,
+        admin_session_id: SessionId,
+        admin_user_id: UserId,
+        level: String,
+    ) -> io::Result<Result<()>> {
+        info!("Method: set_log_level");
+
+        retry!(
+            self,
+            "set_log_level",
+            self.client
+                .set_log_level(ctx!(self), admin_session_id, admin_user_id, level.clone())
         )
     }
 
     // TODO
 }
+
+/// When an event stream should poll the server next.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum NextPoll {
+    Now,
+    AfterError,
+    Never,
+}
+
+/// Repeatedly long polls with the given client, yielding each event received.
+///
+/// After a failed poll it waits before trying again, so consumers don't spin
+/// against an unreachable server. If the session is rejected, the stream ends.
+fn event_stream<T, F>(client: Client, cursor: u64, poll: F) -> impl Stream<Item = io::Result<T>>
+where
+    F: for<'a> Fn(&'a mut Client, u64) -> BoxFuture<'a, io::Result<Result<EventBatch<T>>>>,
+{
+    let state = (client, cursor, VecDeque::new(), poll, NextPoll::Now);
+
+    stream::unfold(
+        state,
+        |(mut client, mut cursor, mut pending, poll, mut next)| async move {
+            loop {
+                if let Some(event) = pending.pop_front() {
+                    return Some((Ok(event), (client, cursor, pending, poll, next)));
+                }
+
+                match next {
+                    NextPoll::Now => (),
+                    NextPoll::AfterError => delay_for(EVENT_POLL_RETRY).await,
+                    NextPoll::Never => return None,
+                }
+
+                let error = match poll(&mut client, cursor).await {
+                    Ok(Ok(batch)) => {
+                        cursor = batch.cursor;
+                        pending.extend(batch.events);
+                        next = NextPoll::Now;
+                        continue;
+                    }
+                    Ok(Err(error)) => {
+                        next = NextPoll::Never;
+
+                        io::Error::new(
+                            io::ErrorKind::PermissionDenied,
+                            format!("Session rejected, no longer polling: {:?}", error),
+                        )
+                    }
+                    Err(error) => {
+                        next = NextPoll::AfterError;
+                        error
+                    }
+                };
+
+                return Some((Err(error), (client, cursor, pending, poll, next)));
+            }
+        },
+    )
+}
+
+/// Whether a call failed because the connection is gone, rather than on the server.
+fn connection_lost(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::ConnectionReset | io::ErrorKind::BrokenPipe
+    )
+}
+
+fn unix_time(time: SystemTime) -> f64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .expect("System time before epoch")
+        .as_secs_f64()
+}
+
+/// Estimates the server's clock skew from a time read between `before` and `after`,
+/// warning if it is larger than `max_skew`.
+fn check_skew(server_time: f64, before: f64, after: f64, max_skew: Duration) -> ClockSkew {
+    let local_time = (before + after) / 2.0;
+    let skew = ClockSkew::from_secs_f64(server_time - local_time);
+
+    if skew.offset > max_skew {
+        warn!(
+            "Server clock is off by {:.3} seconds (maximum {:.3})",
+            skew.as_secs_f64(),
+            max_skew.as_secs_f64(),
+        );
+    }
+
+    skew
+}
+
+#[cold]
+fn protocol_mismatch_error(previous: &str, current: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "Protocol mismatch after reconnecting: server changed from {} to {}",
+            previous, current,
+        ),
+    )
+}
+
+/// Builds a context for a call made once with the given timeout.
+fn single_context(call_timeout: Duration) -> Context {
+    let mut ctx = context::current();
+    ctx.deadline = wire_deadline(SystemTime::now() + call_timeout);
+    ctx
+}
+
+/// Rounds a deadline up to a whole second. tarpc sends deadlines to the server
+/// in whole seconds, dropping the rest, which would otherwise leave the server
+/// with up to a second less than the client waits, or none at all.
+fn wire_deadline(deadline: SystemTime) -> SystemTime {
+    let since_epoch = deadline
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("System time before epoch");
+
+    let mut seconds = since_epoch.as_secs();
+    if since_epoch.subsec_nanos() > 0 {
+        seconds += 1;
+    }
+
+    SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)
+}
+
+/// Waits for a call made without retrying or reconnecting.
+async fn call_once<F, T>(call_timeout: Duration, call: F) -> io::Result<T>
+where
+    F: Future<Output = io::Result<T>>,
+{
+    match timeout(call_timeout, call).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "Remote server not responding in time",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::log_capture::{capture, capture_async, warnings};
+    use crate::transport::JsonCodec;
+    use crate::{Config, MockServer};
+    use bytes::{Bytes, BytesMut};
+    use futures::{SinkExt, StreamExt};
+    use serde::{Deserialize, Serialize};
+    use std::pin::Pin;
+    use tokio::net::TcpListener;
+    use tokio_serde::{Deserializer, Serializer};
+
+    fn warnings_from<F: FnOnce() -> R, R>(f: F) -> (R, Vec<String>) {
+        let (result, messages) = capture(f);
+        let warnings = warnings(&messages).into_iter().map(String::from).collect();
+
+        (result, warnings)
+    }
+
+    #[test]
+    fn skew_from_midpoint() {
+        let max_skew = Duration::from_secs(5);
+
+        let (skew, _) = warnings_from(|| check_skew(1000.0, 999.0, 1001.0, max_skew));
+        assert_eq!(skew, ClockSkew::default());
+
+        let (skew, _) = warnings_from(|| check_skew(1003.0, 999.0, 1001.0, max_skew));
+        assert_eq!(skew.offset, Duration::from_secs(3));
+        assert!(skew.server_ahead);
+        assert_eq!(skew.as_secs_f64(), 3.0);
+
+        let (skew, _) = warnings_from(|| check_skew(997.5, 999.0, 1001.0, max_skew));
+        assert_eq!(skew.offset, Duration::from_millis(2500));
+        assert!(!skew.server_ahead);
+        assert_eq!(skew.as_secs_f64(), -2.5);
+    }
+
+    #[test]
+    fn skew_warning() {
+        let max_skew = Duration::from_secs(5);
+
+        let (_, warnings) = warnings_from(|| check_skew(1004.0, 1000.0, 1000.0, max_skew));
+        assert!(warnings.is_empty(), "Unexpected warnings: {:?}", warnings);
+
+        let (_, warnings) = warnings_from(|| check_skew(1006.0, 1000.0, 1000.0, max_skew));
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("Server clock is off by 6.000 seconds"));
+
+        let (_, warnings) = warnings_from(|| check_skew(994.0, 1000.0, 1000.0, max_skew));
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("Server clock is off by -6.000 seconds"));
+
+        // The threshold is configurable
+        let max_skew = Duration::from_secs(1);
+        let (_, warnings) = warnings_from(|| check_skew(1004.0, 1000.0, 1000.0, max_skew));
+        assert_eq!(warnings.len(), 1);
+    }
+
+    #[test]
+    fn unix_time_epoch() {
+        assert_eq!(unix_time(SystemTime::UNIX_EPOCH), 0.0);
+        assert_eq!(
+            unix_time(SystemTime::UNIX_EPOCH + Duration::from_millis(1500)),
+            1.5,
+        );
+    }
+
+    fn localhost() -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 0))
+    }
+
+    /// Stands in for a server with the given protocol version and clock offset in seconds.
+    /// Answers only the calls a client makes to connect, and pings, recording their contexts.
+    #[derive(Debug, Clone)]
+    struct FakeServer {
+        protocol: &'static str,
+        clock_offset: f64,
+        contexts: Arc<Mutex<Vec<Context>>>,
+    }
+
+    impl FakeServer {
+        fn new(protocol: &'static str, clock_offset: f64) -> Self {
+            FakeServer {
+                protocol,
+                clock_offset,
+                contexts: Arc::new(Mutex::new(Vec::new())),
+            }
+        }
+
+        fn contexts(&self) -> Vec<Context> {
+            self.contexts.lock().unwrap().clone()
+        }
+
+        /// Listens on the given address, returning the address bound.
+        async fn start(&self, address: SocketAddr) -> SocketAddr {
+            let mut listener = TcpListener::bind(address).await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let fake = self.clone();
+
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(fake.clone().serve(stream));
+                }
+            });
+
+            address
+        }
+
+        async fn serve(self, stream: TcpStream) {
+            let codec = TransportFactory::<
+                ClientMessage<DeepwellRequest>,
+                Response<DeepwellResponse>,
+            >::codec(&JsonTransport);
+            let mut transport = tcp::new(stream, codec);
+
+            while let Some(Ok(message)) = transport.next().await {
+                let request = match message {
+                    ClientMessage::Request(request) => request,
+                    _ => continue,
+                };
+
+                self.contexts.lock().unwrap().push(request.context);
+
+                let response = match request.message {
+                    DeepwellRequest::Handshake { .. } => {
+                        DeepwellResponse::Handshake(Ok(str!(self.protocol)))
+                    }
+                    DeepwellRequest::Protocol { .. } => {
+                        DeepwellResponse::Protocol(str!(self.protocol))
+                    }
+                    DeepwellRequest::Time { .. } => {
+                        let time = unix_time(SystemTime::now()) + self.clock_offset;
+                        DeepwellResponse::Time(time)
+                    }
+                    DeepwellRequest::Ping { .. } => DeepwellResponse::Ping(Ok(())),
+                    _ => continue,
+                };
+
+                // tarpc doesn't allow constructing responses directly
+                let response = serde_json::json!({
+                    "request_id": request.id,
+                    "message": { "Ok": response },
+                });
+                let response = serde_json::from_value(response).unwrap();
+
+                if transport.send(response).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn tcp_nodelay() {
+        let listener = TcpListener::bind(localhost()).await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let stream = open_stream(address, true).await.unwrap();
+        assert!(stream.nodelay().unwrap());
+
+        let stream = open_stream(address, false).await.unwrap();
+        assert!(!stream.nodelay().unwrap());
+    }
+
+    async fn connect(server: &MockServer) -> Client {
+        ClientBuilder::new(server.address(), Duration::from_secs(2))
+            .connect()
+            .await
+            .expect("Unable to connect to mock server")
+    }
+
+    #[tokio::test]
+    async fn mock_calls() {
+        let server = MockServer::start(&Config::testing()).await.unwrap();
+        let user_id = server
+            .mock()
+            .add_user("alice", "alice@example.com", "hunter2");
+        let mut client = connect(&server).await;
+
+        assert_eq!(client.ping().await.unwrap(), Ok(()));
+        assert_eq!(client.server_protocol(), PROTOCOL_VERSION);
+
+        let user = client.get_user_from_id(user_id).await.unwrap().unwrap();
+        assert_eq!(user.map(|user| user.id()), Some(user_id));
+    }
+
+    #[tokio::test]
+    async fn email_verified() {
+        let server = MockServer::start(&Config::testing()).await.unwrap();
+        let user_id = server
+            .mock()
+            .add_user("alice", "alice@example.com", "hunter2");
+        let mut client = connect(&server).await;
+
+        let verified = client.is_email_verified(user_id).await.unwrap();
+        assert_eq!(verified, Ok(false));
+
+        server.mock().verify_email(user_id);
+        let verified = client.is_email_verified(user_id).await.unwrap();
+        assert_eq!(verified, Ok(true));
+
+        let unknown = UserId::from_raw(user_id.to_i64() + 1);
+        let result = client.is_email_verified(unknown).await.unwrap();
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn get_or_create_user() {
+        let server = MockServer::start(&Config::testing()).await.unwrap();
+        let mut client = connect(&server).await;
+
+        let (user_id, created) = client
+            .get_or_create_user(str!("bob"), str!("bob@example.com"), str!("hunter2"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(created);
+
+        let user = client
+            .get_user_from_name(str!("bob"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.map(|user| user.id()), Some(user_id));
+
+        // The same details get the existing user
+        let result = client
+            .get_or_create_user(str!("bob"), str!("bob@example.com"), str!("hunter2"))
+            .await
+            .unwrap();
+        assert_eq!(result, Ok((user_id, false)));
+
+        // But not with the wrong password
+        let result = client
+            .get_or_create_user(str!("bob"), str!("bob@example.com"), str!("wrong"))
+            .await
+            .unwrap();
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn logout_event() {
+        let server = MockServer::start(&Config::testing()).await.unwrap();
+        server
+            .mock()
+            .add_user("alice", "alice@example.com", "hunter2");
+        let mut client = connect(&server).await;
+        let mut other = connect(&server).await;
+
+        let session = client
+            .login(str!("alice"), str!("hunter2"), None)
+            .await
+            .unwrap()
+            .unwrap();
+        let ended = other
+            .login(str!("alice"), str!("hunter2"), None)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let events = client
+            .session_events(session.session_id(), session.user_id())
+            .await
+            .unwrap()
+            .unwrap();
+        futures::pin_mut!(events);
+
+        let result = other.logout(ended.session_id(), ended.user_id()).await;
+        assert_eq!(result.unwrap(), Ok(()));
+
+        let event = timeout(Duration::from_secs(1), events.next())
+            .await
+            .expect("No event received")
+            .unwrap()
+            .unwrap();
+        assert_eq!(event.session_id, ended.session_id());
+        assert_eq!(event.user_id, ended.user_id());
+    }
+
+    #[tokio::test]
+    async fn no_auto_reconnect() {
+        let mut server = MockServer::start(&Config::testing()).await.unwrap();
+        let mut client = ClientBuilder::new(server.address(), Duration::from_millis(200))
+            .auto_reconnect(false)
+            .connect()
+            .await
+            .unwrap();
+
+        // A timeout is returned without retrying
+        server.mock().hang_requests(1);
+        let error = client.ping().await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(client.stats().retries(), 0);
+
+        // So is a lost connection
+        server.stop().await;
+        let error = client.ping().await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(client.stats().retries(), 0);
+        assert_eq!(client.stats().reconnects(), 0);
+    }
+
+    #[tokio::test]
+    async fn retry_stats() {
+        let server = MockServer::start(&Config::testing()).await.unwrap();
+        let mut client = ClientBuilder::new(server.address(), Duration::from_millis(200))
+            .connect()
+            .await
+            .unwrap();
+
+        assert_eq!(client.ping().await.unwrap(), Ok(()));
+
+        // Times out once, then succeeds on a new connection
+        server.mock().hang_requests(1);
+        assert_eq!(client.ping().await.unwrap(), Ok(()));
+        assert_eq!(
+            client.is_username_available(str!("alice")).await.unwrap(),
+            Ok(true)
+        );
+
+        let stats = client.stats();
+        assert_eq!(stats.calls(), 3);
+        assert_eq!(stats.retries(), 1);
+        assert_eq!(stats.reconnects(), 1);
+
+        let ping = stats.method("ping").unwrap();
+        assert_eq!(ping.calls, 2);
+        assert_eq!(ping.retries, 1);
+        assert!(ping.total_time >= Duration::from_millis(200));
+
+        let available = stats.method("is_username_available").unwrap();
+        assert_eq!(available.calls, 1);
+        assert_eq!(available.retries, 0);
+    }
+
+    #[tokio::test]
+    async fn last_call_flags() {
+        let server = MockServer::start(&Config::testing()).await.unwrap();
+        let mut client = ClientBuilder::new(server.address(), Duration::from_millis(200))
+            .connect()
+            .await
+            .unwrap();
+
+        server.mock().hang_requests(1);
+        assert_eq!(client.ping().await.unwrap(), Ok(()));
+        assert!(client.last_call_retried());
+        assert!(client.last_call_reconnected());
+
+        // Cleared by the next call
+        assert_eq!(client.ping().await.unwrap(), Ok(()));
+        assert!(!client.last_call_retried());
+        assert!(!client.last_call_reconnected());
+    }
+
+    #[tokio::test]
+    async fn connection_addresses() {
+        let server = MockServer::start(&Config::testing()).await.unwrap();
+        let mut client = ClientBuilder::new(server.address(), Duration::from_millis(200))
+            .connect()
+            .await
+            .unwrap();
+
+        assert_eq!(client.peer_addr(), server.address());
+        assert!(client.local_addr().ip().is_loopback());
+        assert_ne!(client.local_addr().port(), 0);
+
+        // Reconnecting opens a new connection, from a different port
+        let local_addr = client.local_addr();
+        server.mock().hang_requests(1);
+        assert_eq!(client.ping().await.unwrap(), Ok(()));
+        assert!(client.last_call_reconnected());
+        assert_eq!(client.peer_addr(), server.address());
+        assert_ne!(client.local_addr(), local_addr);
+    }
+
+    #[tokio::test]
+    async fn retry_budget_exhausted() {
+        let server = MockServer::start(&Config::testing()).await.unwrap();
+        let builder =
+            ClientBuilder::new(server.address(), Duration::from_millis(100)).retry_budget(0.001, 2);
+        let mut first = builder.clone().connect().await.unwrap();
+        let mut second = builder.connect().await.unwrap();
+
+        server.mock().hang_requests(usize::MAX);
+
+        // Two retries are allowed, then calls fail after their first attempt
+        let error = first.ping().await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(first.stats().retries(), 2);
+
+        let error = first.ping().await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(first.stats().retries(), 2);
+
+        // Including on other clients from the same builder
+        let error = second.ping().await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(second.stats().retries(), 0);
+    }
+
+    #[tokio::test]
+    async fn method_timeouts() {
+        let server = MockServer::start(&Config::testing()).await.unwrap();
+        let mut client = ClientBuilder::new(server.address(), Duration::from_secs(10))
+            .method_timeout("ping", Duration::from_millis(100))
+            .method_timeout("get_users_from_ids", Duration::from_millis(800))
+            .auto_reconnect(false)
+            .connect()
+            .await
+            .unwrap();
+
+        server.mock().hang_requests(2);
+
+        let started = Instant::now();
+        let error = client.ping().await.unwrap_err();
+        let elapsed = started.elapsed();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(
+            elapsed < Duration::from_millis(500),
+            "ping took {:?}",
+            elapsed
+        );
+
+        let started = Instant::now();
+        let error = client.get_users_from_ids(vec![]).await.unwrap_err();
+        let elapsed = started.elapsed();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(
+            elapsed >= Duration::from_millis(800),
+            "lookup took {:?}",
+            elapsed
+        );
+        assert!(
+            elapsed < Duration::from_secs(5),
+            "lookup took {:?}",
+            elapsed
+        );
+
+        // Others use the default
+        assert_eq!(client.timeout_for("time"), Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn server_protocol_cached() {
+        let server = MockServer::start(&Config::testing()).await.unwrap();
+        let mut client = connect(&server).await;
+
+        // Known from connecting, without a call
+        assert_eq!(client.server_protocol(), PROTOCOL_VERSION);
+        assert_eq!(client.stats().calls(), 0);
+
+        assert_eq!(client.protocol().await.unwrap(), PROTOCOL_VERSION);
+        assert_eq!(client.server_protocol(), PROTOCOL_VERSION);
+        assert_eq!(client.stats().calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn warm_up() {
+        let server = MockServer::start(&Config::testing()).await.unwrap();
+        let mut client = ClientBuilder::new(server.address(), Duration::from_secs(2))
+            .warm_up(true)
+            .connect()
+            .await
+            .unwrap();
+
+        let info = client.warm_up().await.unwrap();
+        assert!(info.latency < Duration::from_secs(1), "{:?}", info);
+        assert!(
+            info.clock_skew.offset < Duration::from_secs(1),
+            "{:?}",
+            info
+        );
+
+        // Connecting fails if the server can't answer a ping
+        server.mock().hang_requests(1);
+        let error = ClientBuilder::new(server.address(), Duration::from_millis(200))
+            .warm_up(true)
+            .connect()
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+
+        // But not without warming up
+        server.mock().hang_requests(1);
+        ClientBuilder::new(server.address(), Duration::from_millis(200))
+            .connect()
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn custom_context() {
+        let server = MockServer::start(&Config::testing()).await.unwrap();
+        let mut client = connect(&server).await;
+
+        let mut ctx = context::current();
+        ctx.deadline = SystemTime::now() + Duration::from_secs(5);
+        let result = client
+            .call_with_context(ctx, |mut raw, ctx| async move { raw.ping(ctx).await })
+            .await;
+        assert_eq!(result.unwrap(), Ok(()));
+
+        // The context's deadline is used instead of the client's timeout, without retrying
+        server.mock().hang_requests(1);
+        let mut ctx = context::current();
+        ctx.deadline = SystemTime::now() + Duration::from_millis(100);
+
+        let started = Instant::now();
+        let error = client
+            .call_with_context(ctx, |mut raw, ctx| async move { raw.ping(ctx).await })
+            .await
+            .unwrap_err();
+        let elapsed = started.elapsed();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(elapsed < Duration::from_secs(1), "call took {:?}", elapsed);
+        assert_eq!(client.stats().retries(), 0);
+    }
+
+    #[tokio::test]
+    async fn protocol_changed() {
+        let mut server = MockServer::start(&Config::testing()).await.unwrap();
+        let mut client = ClientBuilder::new(server.address(), Duration::from_millis(200))
+            .connect()
+            .await
+            .unwrap();
+        assert!(!client.protocol_mismatch());
+
+        // Replace the server with one speaking another version
+        server.stop().await;
+        FakeServer::new("2", 0.0).start(server.address()).await;
+
+        let error = client.ping().await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(client.protocol_mismatch());
+        assert_eq!(client.server_protocol(), PROTOCOL_VERSION);
+
+        // Further calls are refused without reconnecting
+        let reconnects = client.stats().reconnects();
+        let error = client.ping().await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(client.stats().reconnects(), reconnects);
+    }
+
+    #[tokio::test]
+    async fn connect_skew_warning() {
+        let fake = FakeServer::new(PROTOCOL_VERSION, 60.0);
+        let address = fake.start(localhost()).await;
+
+        let (client, messages) =
+            capture_async(ClientBuilder::new(address, Duration::from_secs(2)).connect()).await;
+        let client = client.unwrap();
+        let skew = client.connect_clock_skew();
+        assert!(skew.server_ahead);
+        assert!(skew.offset > Duration::from_secs(59), "{:?}", skew);
+
+        let logged = warnings(&messages);
+        assert_eq!(logged.len(), 1, "{:?}", logged);
+        assert!(logged[0].starts_with("Server clock is off by 60."));
+
+        // Not when it's within the configured maximum
+        let builder = ClientBuilder::new(address, Duration::from_secs(2))
+            .max_clock_skew(Duration::from_secs(120));
+        let (client, messages) = capture_async(builder.connect()).await;
+        client.unwrap();
+        assert!(warnings(&messages).is_empty(), "{:?}", messages);
+    }
+
+    /// JSON with every byte inverted, which a default client or server can't read.
+    #[derive(Debug, Copy, Clone)]
+    struct InvertedTransport;
+
+    impl<Item, SinkItem> TransportFactory<Item, SinkItem> for InvertedTransport
+    where
+        Item: for<'de> Deserialize<'de> + Unpin + Send + 'static,
+        SinkItem: Serialize + Unpin + Send + 'static,
+    {
+        type Codec = InvertedCodec<Item, SinkItem>;
+
+        fn codec(&self) -> Self::Codec {
+            InvertedCodec(TransportFactory::<Item, SinkItem>::codec(&JsonTransport))
+        }
+    }
+
+    #[derive(Debug)]
+    struct InvertedCodec<Item, SinkItem>(JsonCodec<Item, SinkItem>);
+
+    fn invert(bytes: &[u8]) -> Vec<u8> {
+        bytes.iter().map(|byte| !byte).collect()
+    }
+
+    impl<Item, SinkItem> Serializer<SinkItem> for InvertedCodec<Item, SinkItem>
+    where
+        Item: Unpin,
+        SinkItem: Serialize + Unpin,
+    {
+        type Error = io::Error;
+
+        fn serialize(self: Pin<&mut Self>, item: &SinkItem) -> io::Result<Bytes> {
+            let bytes = Pin::new(&mut self.get_mut().0).serialize(item)?;
+
+            Ok(Bytes::from(invert(&bytes)))
+        }
+    }
+
+    impl<Item, SinkItem> Deserializer<Item> for InvertedCodec<Item, SinkItem>
+    where
+        Item: for<'de> Deserialize<'de> + Unpin,
+        SinkItem: Unpin,
+    {
+        type Error = io::Error;
+
+        fn deserialize(self: Pin<&mut Self>, src: &BytesMut) -> io::Result<Item> {
+            let src = BytesMut::from(&invert(src)[..]);
+
+            Pin::new(&mut self.get_mut().0).deserialize(&src)
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_transport() {
+        let config = Config::testing();
+        let server = MockServer::start_with_transport(&config, InvertedTransport)
+            .await
+            .unwrap();
+
+        let mut client = ClientBuilder::new(server.address(), Duration::from_secs(2))
+            .transport(InvertedTransport)
+            .connect()
+            .await
+            .unwrap();
+        assert_eq!(client.ping().await.unwrap(), Ok(()));
+        assert_eq!(client.server_protocol(), PROTOCOL_VERSION);
+
+        // The default codec can't talk to it
+        let result = ClientBuilder::new(server.address(), Duration::from_millis(200))
+            .connect()
+            .await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn trace_id_logged() {
+        let server = MockServer::start(&Config::testing()).await.unwrap();
+        let mut client = connect(&server).await;
+
+        let trace_id = *context::current().trace_id();
+        client.set_trace_id(Some(trace_id));
+
+        let (result, messages) = capture_async(client.ping()).await;
+        assert_eq!(result.unwrap(), Ok(()));
+
+        // Logged by the server, which runs on this thread
+        let line = format!("Method: ping [{}]", trace_id);
+        assert!(
+            messages.iter().any(|(_, message)| *message == line),
+            "No {:?} in {:?}",
+            line,
+            messages,
+        );
+    }
+
+    #[test]
+    fn wire_deadlines() {
+        let second = |seconds| SystemTime::UNIX_EPOCH + Duration::from_secs(seconds);
+
+        assert_eq!(wire_deadline(second(100)), second(100));
+        assert_eq!(
+            wire_deadline(second(100) + Duration::from_millis(1)),
+            second(101),
+        );
+        assert_eq!(
+            wire_deadline(second(100) + Duration::from_millis(999)),
+            second(101),
+        );
+    }
+
+    #[tokio::test]
+    async fn deadline_propagated() {
+        // Short timeouts still reach the backend, though deadlines are sent in whole seconds
+        let server = MockServer::start(&Config::testing()).await.unwrap();
+        let mut client = ClientBuilder::new(server.address(), Duration::from_millis(200))
+            .connect()
+            .await
+            .unwrap();
+
+        for _ in 0..5 {
+            assert_eq!(client.ping().await.unwrap(), Ok(()));
+        }
+
+        // The server is told when the client stops waiting, not tarpc's default of ten seconds
+        let fake = FakeServer::new(PROTOCOL_VERSION, 0.0);
+        let address = fake.start(localhost()).await;
+        let mut client = ClientBuilder::new(address, Duration::from_secs(30))
+            .connect()
+            .await
+            .unwrap();
+
+        let sent = SystemTime::now();
+        assert_eq!(client.ping().await.unwrap(), Ok(()));
+
+        let deadline = fake.contexts().last().unwrap().deadline;
+        assert!(deadline >= sent + Duration::from_secs(30));
+        assert!(deadline <= SystemTime::now() + Duration::from_secs(31));
+    }
+
+    #[test]
+    fn lost_connection() {
+        use io::ErrorKind;
+
+        assert!(connection_lost(&ErrorKind::ConnectionReset.into()));
+        assert!(connection_lost(&ErrorKind::BrokenPipe.into()));
+
+        // Timeouts are handled separately, and other errors aren't retried
+        assert!(!connection_lost(&ErrorKind::TimedOut.into()));
+        assert!(!connection_lost(&ErrorKind::InvalidData.into()));
+    }
+}
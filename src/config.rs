@@ -18,19 +18,28 @@
  * along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
 
+use crate::server::CONFIGURABLE_METHODS;
+use deepwell_core::prelude::UserId;
 use log::LevelFilter;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display};
 use std::fs::File;
-use std::io::Read;
+use std::io::{self, Read};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
 use structopt::StructOpt;
 
 const DEFAULT_PORT: u16 = 2747;
 const DEFAULT_LOG_LEVEL: LevelFilter = LevelFilter::Info;
+const DEFAULT_CONNECTION_LOG_LEVEL: LevelFilter = LevelFilter::Debug;
+const DEFAULT_MAX_REQUEST_ITEMS: usize = 100;
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 30;
 
 // Structopt argument parsing
 
-#[derive(Debug, StructOpt)]
+#[derive(Debug, Clone, StructOpt)]
 #[structopt(
     name = "deepwell-rpc",
     about = "RPC server to provide database management and migrations"
@@ -40,32 +49,258 @@ struct Options {
     #[structopt(short, long)]
     level: Option<LevelFilter>,
 
+    /// IP address to listen on, overriding the configuration file.
+    #[structopt(short, long)]
+    address: Option<IpAddr>,
+
+    /// Port to listen on, overriding the configuration file.
+    #[structopt(short, long)]
+    port: Option<u16>,
+
+    /// Serve canned responses instead of connecting to the database, for protocol testing.
+    #[cfg(feature = "testing")]
+    #[structopt(long)]
+    mock_backend: bool,
+
     /// Configuration file.
     #[structopt(name = "CONFIG_FILE", parse(from_os_str))]
     config_file: PathBuf,
 }
 
+// Errors
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse {
+        error: toml::de::Error,
+        location: Option<(usize, usize, String)>,
+    },
+    Invalid {
+        key: &'static str,
+        message: String,
+    },
+}
+
+impl ConfigError {
+    #[cold]
+    fn parse(contents: &str, error: toml::de::Error) -> Self {
+        // toml gives zero-based positions, convert to one-based for humans
+        let location = error.line_col().map(|(line, column)| {
+            let snippet = contents.lines().nth(line).unwrap_or("");
+
+            (line + 1, column + 1, str!(snippet))
+        });
+
+        ConfigError::Parse { error, location }
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(error) => write!(f, "Unable to read config file: {}", error),
+            ConfigError::Parse {
+                error,
+                location: Some((line, column, snippet)),
+            } => {
+                writeln!(
+                    f,
+                    "Unable to parse config file at line {}, column {}: {}",
+                    line, column, error,
+                )?;
+                writeln!(f, "{:>5} | {}", line, snippet)?;
+                write!(f, "{:>5} | {:>width$}", "", "^", width = column)
+            }
+            ConfigError::Parse {
+                error,
+                location: None,
+            } => write!(f, "Unable to parse config file: {}", error),
+            ConfigError::Invalid { key, message } => {
+                write!(f, "Invalid value for '{}' in config file: {}", key, message)
+            }
+        }
+    }
+}
+
+// Log levels
+
+/// Looks up a log level by name, ignoring case.
+pub fn find_log_level(log_level: &str) -> Option<LevelFilter> {
+    const LEVELS: [(&str, LevelFilter); 9] = [
+        ("off", LevelFilter::Off),
+        ("none", LevelFilter::Off),
+        ("trace", LevelFilter::Trace),
+        ("debug", LevelFilter::Debug),
+        ("info", LevelFilter::Info),
+        ("warn", LevelFilter::Warn),
+        ("warning", LevelFilter::Warn),
+        ("err", LevelFilter::Error),
+        ("error", LevelFilter::Error),
+    ];
+
+    LEVELS
+        .iter()
+        .find(|(text, _)| log_level.eq_ignore_ascii_case(text))
+        .map(|(_, level)| *level)
+}
+
 // Configuration objects
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub address: SocketAddr,
     pub log_level: LevelFilter,
+    pub connection_log_level: LevelFilter,
+    pub max_request_items: usize,
+    pub reject_when_busy: bool,
+    pub tcp_nodelay: bool,
+    pub shutdown_timeout: Duration,
+    pub log_payloads: bool,
+    pub deduplicate_reads: bool,
+    pub min_client_protocol: Option<u32>,
+    pub read_only: bool,
     pub database_url: String,
     pub revisions_dir: PathBuf,
     pub password_blacklist: Option<PathBuf>,
+    pub administrators: HashSet<UserId>,
+    pub rate_limits: HashMap<String, f64>,
+    pub client_rate_limits: HashMap<String, f64>,
+    pub disabled_methods: HashSet<String>,
+
+    #[cfg(feature = "testing")]
+    pub mock_backend: bool,
+
+    options: Options,
 }
 
 impl Config {
     #[cold]
     pub fn parse_args() -> Self {
-        let opts = Options::from_args();
-        let mut config: Self = ConfigFile::read(&opts.config_file).into();
+        match Self::load(Options::from_args()) {
+            Ok(config) => config,
+            Err(errors) => {
+                for error in &errors {
+                    eprintln!("{}", error);
+                }
+
+                process::exit(1);
+            }
+        }
+    }
+
+    /// Reads the configuration file again, keeping the same command-line overrides.
+    #[cold]
+    pub fn reload(&self) -> Result<Self, Vec<ConfigError>> {
+        Self::load(self.options.clone())
+    }
+
+    #[cold]
+    fn load(opts: Options) -> Result<Self, Vec<ConfigError>> {
+        let config_file = ConfigFile::read(&opts.config_file).map_err(|error| vec![error])?;
+
+        // Report every problem at once, rather than one per attempt
+        let errors = config_file.validate();
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let mut config = config_file.into_config(opts.clone());
         if let Some(level) = opts.level {
             config.log_level = level;
         }
 
-        config
+        if let Some(ip) = opts.address {
+            config.address.set_ip(ip);
+        }
+
+        if let Some(port) = opts.port {
+            config.address.set_port(port);
+        }
+
+        Ok(config)
+    }
+
+    /// Returns the host part of the database URL, without any credentials.
+    ///
+    /// Connection strings which are not URLs, such as `host=db password=...`,
+    /// can have credentials anywhere, so they are redacted entirely.
+    pub fn database_host(&self) -> &str {
+        let url = self.database_url.as_str();
+        let url = match url.find("://") {
+            Some(idx) => &url[idx + 3..],
+            None => return "<redacted>",
+        };
+
+        let url = url.rfind('@').map(|idx| &url[idx + 1..]).unwrap_or(url);
+        let host = match url.find(&['/', '?'][..]) {
+            Some(idx) => &url[..idx],
+            None => url,
+        };
+
+        // No host means the default, a local socket
+        if host.is_empty() {
+            "localhost"
+        } else {
+            host
+        }
+    }
+
+    /// Logs the effective configuration, with the database URL and blacklist path redacted.
+    #[cold]
+    pub fn log(&self) {
+        fn redacted<T>(value: &Option<T>) -> &'static str {
+            match value {
+                Some(_) => "<redacted>",
+                None => "none",
+            }
+        }
+
+        info!("Configuration:");
+        info!("  Address: {}", self.address);
+        info!("  Log level: {}", self.log_level);
+        info!("  Connection log level: {}", self.connection_log_level);
+        info!("  Log payloads: {}", self.log_payloads);
+        info!("  Deduplicate reads: {}", self.deduplicate_reads);
+
+        match self.min_client_protocol {
+            Some(version) => info!("  Minimum client protocol: {}", version),
+            None => info!("  Minimum client protocol: none"),
+        }
+
+        info!("  Max request items: {}", self.max_request_items);
+        info!("  Reject when busy: {}", self.reject_when_busy);
+        info!("  TCP no-delay: {}", self.tcp_nodelay);
+        info!(
+            "  Shutdown timeout: {} seconds",
+            self.shutdown_timeout.as_secs(),
+        );
+        info!("  Read-only: {}", self.read_only);
+        info!("  Database URL: <redacted>");
+        info!("  Revisions directory: {}", self.revisions_dir.display());
+        info!(
+            "  Password blacklist: {}",
+            redacted(&self.password_blacklist),
+        );
+
+        for user_id in &self.administrators {
+            info!("  Administrator: user ID {}", user_id);
+        }
+
+        for (method, rate) in &self.rate_limits {
+            info!("  Rate limit for {}: {} per second", method, rate);
+        }
+
+        for (method, rate) in &self.client_rate_limits {
+            info!(
+                "  Rate limit for {}: {} per second per client",
+                method, rate,
+            );
+        }
+
+        for method in &self.disabled_methods {
+            info!("  Disabled method: {}", method);
+        }
     }
 }
 
@@ -73,6 +308,12 @@ impl Config {
 #[derive(Deserialize, Debug)]
 struct App {
     log_level: Option<String>,
+    log_payloads: Option<bool>,
+    deduplicate_reads: Option<bool>,
+    min_client_protocol: Option<u32>,
+
+    #[serde(default)]
+    disabled_methods: HashSet<String>,
 }
 
 #[serde(rename_all = "kebab-case")]
@@ -80,6 +321,11 @@ struct App {
 struct Network {
     use_ipv6: bool,
     port: Option<u16>,
+    connection_log_level: Option<String>,
+    max_request_items: Option<usize>,
+    reject_when_busy: Option<bool>,
+    tcp_nodelay: Option<bool>,
+    shutdown_timeout: Option<u64>,
 }
 
 #[serde(rename_all = "kebab-case")]
@@ -87,12 +333,16 @@ struct Network {
 struct Data {
     database_url: String,
     revisions_dir: PathBuf,
+    read_only: Option<bool>,
 }
 
 #[serde(rename_all = "kebab-case")]
 #[derive(Deserialize, Debug)]
 struct Security {
     password_blacklist_file: PathBuf,
+
+    #[serde(default)]
+    administrators: HashSet<UserId>,
 }
 
 #[serde(rename_all = "kebab-case")]
@@ -102,68 +352,140 @@ struct ConfigFile {
     network: Network,
     data: Data,
     security: Security,
+
+    #[serde(default)]
+    rate_limits: HashMap<String, f64>,
+
+    #[serde(default)]
+    client_rate_limits: HashMap<String, f64>,
 }
 
 impl ConfigFile {
     #[cold]
-    fn read(path: &Path) -> Self {
-        let mut file = File::open(path).expect("Unable to open config file");
+    fn read(path: &Path) -> Result<Self, ConfigError> {
+        let mut file = File::open(path).map_err(ConfigError::Io)?;
         let mut contents = String::new();
         let _ = file
             .read_to_string(&mut contents)
-            .expect("Unable to read config file");
-
-        let obj: Self = toml::from_str(&contents).expect("Unable to parse TOML in config file");
+            .map_err(ConfigError::Io)?;
 
-        obj
+        toml::from_str(&contents).map_err(|error| ConfigError::parse(&contents, error))
     }
 
+    /// Checks values which parse but are not usable, returning all of the problems found.
     #[cold]
-    fn parse_log_level(log_level: Option<&str>) -> LevelFilter {
-        const LEVELS: [(&str, LevelFilter); 9] = [
-            ("", DEFAULT_LOG_LEVEL),
-            ("off", LevelFilter::Off),
-            ("none", LevelFilter::Off),
-            ("trace", LevelFilter::Trace),
-            ("debug", LevelFilter::Debug),
-            ("warn", LevelFilter::Warn),
-            ("warning", LevelFilter::Warn),
-            ("err", LevelFilter::Error),
-            ("error", LevelFilter::Error),
+    fn validate(&self) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+        let mut invalid = |key: &'static str, message: &str| {
+            errors.push(ConfigError::Invalid {
+                key,
+                message: str!(message),
+            });
+        };
+
+        let log_levels = [
+            ("app.log-level", &self.app.log_level),
+            (
+                "network.connection-log-level",
+                &self.network.connection_log_level,
+            ),
         ];
 
-        let log_level = match log_level {
-            Some(ref log_level) => log_level,
-            None => return DEFAULT_LOG_LEVEL,
-        };
+        for (key, log_level) in &log_levels {
+            if let Some(log_level) = log_level {
+                if !log_level.is_empty() && find_log_level(log_level).is_none() {
+                    invalid(key, "not a recognized log level");
+                }
+            }
+        }
+
+        if self.network.port == Some(0) {
+            invalid("network.port", "must be between 1 and 65535");
+        }
+
+        if self.network.max_request_items == Some(0) {
+            invalid("network.max-request-items", "must be at least 1");
+        }
 
-        for (text, level) in &LEVELS {
-            if log_level.eq_ignore_ascii_case(text) {
-                return *level;
+        if self.data.database_url.is_empty() {
+            invalid("data.database-url", "must not be empty");
+        }
+
+        if self.data.revisions_dir.as_os_str().is_empty() {
+            invalid("data.revisions-dir", "must not be empty");
+        }
+
+        for method in &self.app.disabled_methods {
+            if !CONFIGURABLE_METHODS.contains(&method.as_str()) {
+                invalid(
+                    "app.disabled-methods",
+                    &format!("no such method: {}", method),
+                );
             }
         }
 
-        panic!("No such log level for '{}'", log_level);
+        let rate_limits = [
+            ("rate-limits", &self.rate_limits),
+            ("client-rate-limits", &self.client_rate_limits),
+        ];
+
+        for (key, rates) in &rate_limits {
+            for (method, &rate) in rates.iter() {
+                if !CONFIGURABLE_METHODS.contains(&method.as_str()) {
+                    invalid(key, &format!("no such method: {}", method));
+                }
+
+                // Also rejects NaN
+                if !(rate > 0.0 && rate.is_finite()) {
+                    invalid(key, &format!("rate for {} must be above zero", method));
+                }
+            }
+        }
+
+        errors
+    }
+
+    #[cold]
+    fn parse_log_level(log_level: Option<&str>, default: LevelFilter) -> LevelFilter {
+        let log_level = match log_level {
+            Some(log_level) if !log_level.is_empty() => log_level,
+            _ => return default,
+        };
+
+        // Checked by validate()
+        find_log_level(log_level).expect("Invalid log level in config")
     }
 }
 
-impl Into<Config> for ConfigFile {
+impl ConfigFile {
     #[cold]
-    fn into(self) -> Config {
+    fn into_config(self, options: Options) -> Config {
         let ConfigFile {
             app,
             network,
             data,
             security,
+            rate_limits,
+            client_rate_limits,
         } = self;
 
-        let Network { use_ipv6, port } = network;
+        let Network {
+            use_ipv6,
+            port,
+            connection_log_level,
+            max_request_items,
+            reject_when_busy,
+            tcp_nodelay,
+            shutdown_timeout,
+        } = network;
         let Data {
             database_url,
             revisions_dir,
+            read_only,
         } = data;
         let Security {
             password_blacklist_file,
+            administrators,
         } = security;
 
         let ip_address = if use_ipv6 {
@@ -180,13 +502,310 @@ impl Into<Config> for ConfigFile {
 
         let address = SocketAddr::new(ip_address, port.unwrap_or(DEFAULT_PORT));
         let log_level = app.log_level.as_ref().map(|s| s.as_ref());
+        let connection_log_level = connection_log_level.as_ref().map(|s| s.as_ref());
 
         Config {
             address,
-            log_level: Self::parse_log_level(log_level),
+            log_level: Self::parse_log_level(log_level, DEFAULT_LOG_LEVEL),
+            connection_log_level: Self::parse_log_level(
+                connection_log_level,
+                DEFAULT_CONNECTION_LOG_LEVEL,
+            ),
+            max_request_items: max_request_items.unwrap_or(DEFAULT_MAX_REQUEST_ITEMS),
+            reject_when_busy: reject_when_busy.unwrap_or(false),
+            tcp_nodelay: tcp_nodelay.unwrap_or(true),
+            shutdown_timeout: Duration::from_secs(
+                shutdown_timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT),
+            ),
+            log_payloads: app.log_payloads.unwrap_or(false),
+            deduplicate_reads: app.deduplicate_reads.unwrap_or(false),
+            min_client_protocol: app.min_client_protocol,
+            disabled_methods: app.disabled_methods,
+            read_only: read_only.unwrap_or(false),
             database_url,
             revisions_dir,
             password_blacklist,
+            administrators,
+            rate_limits,
+            client_rate_limits,
+
+            #[cfg(feature = "testing")]
+            mock_backend: options.mock_backend,
+
+            options,
+        }
+    }
+}
+
+// Configuration for tests, which the server binary itself has no use for
+#[cfg(any(test, feature = "mock"))]
+#[allow(dead_code)]
+mod testing {
+    use super::*;
+
+    pub const BASE_CONFIG: &str = r#"
+[app]
+
+[network]
+use-ipv6 = false
+
+[data]
+database-url = "postgres://localhost"
+revisions-dir = "revisions"
+
+[security]
+password-blacklist-file = ""
+"#;
+
+    pub fn options() -> Options {
+        Options {
+            level: None,
+            address: None,
+            port: None,
+            #[cfg(feature = "testing")]
+            mock_backend: false,
+            config_file: PathBuf::from("config.toml"),
+        }
+    }
+
+    pub fn with_database_url(database_url: &str) -> Config {
+        let mut config: ConfigFile = toml::from_str(BASE_CONFIG).expect("Invalid base config");
+        config.data.database_url = str!(database_url);
+        config.into_config(options())
+    }
+
+    impl Config {
+        /// The default configuration, for tests which run a server, such as with `MockServer`.
+        pub fn testing() -> Self {
+            with_database_url("postgres://localhost")
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::testing::*;
+    use super::*;
+    use crate::log_capture::capture;
+    use std::{env, fs};
+
+    fn parse_error(contents: &str) -> ConfigError {
+        match toml::from_str::<ConfigFile>(contents) {
+            Ok(_) => panic!("Config parsed successfully"),
+            Err(error) => ConfigError::parse(contents, error),
+        }
+    }
+
+    #[test]
+    fn parse_error_location() {
+        let contents = BASE_CONFIG.replace("use-ipv6 = false", "use-ipv6 = maybe");
+        let error = parse_error(&contents);
+
+        match &error {
+            ConfigError::Parse {
+                location: Some((line, column, snippet)),
+                ..
+            } => {
+                assert_eq!(*line, 5);
+                assert_eq!(*column, 12);
+                assert_eq!(snippet, "use-ipv6 = maybe");
+            }
+            _ => panic!("No location for parse error: {:?}", error),
+        }
+
+        let message = error.to_string();
+        let lines = message.lines().collect::<Vec<_>>();
+        assert!(lines[0].starts_with("Unable to parse config file at line 5, column 12: "));
+        assert_eq!(lines[1], "    5 | use-ipv6 = maybe");
+        assert_eq!(lines[2], "      |            ^");
+    }
+
+    #[test]
+    fn parse_error_missing_field() {
+        let contents = BASE_CONFIG.replace("use-ipv6 = false", "");
+        let error = parse_error(&contents);
+
+        assert!(
+            error.to_string().contains("use-ipv6"),
+            "Missing field not named: {}",
+            error,
+        );
+    }
+
+    fn load_with(name: &str, options: Options) -> Config {
+        let path =
+            env::temp_dir().join(format!("deepwell-rpc-test-{}-{}.toml", process::id(), name,));
+
+        fs::write(&path, BASE_CONFIG).expect("Unable to write config file");
+        let result = Config::load(Options {
+            config_file: path.clone(),
+            ..options
+        });
+        let _ = fs::remove_file(&path);
+
+        result.expect("Config failed to load")
+    }
+
+    #[test]
+    fn overrides() {
+        let config = load_with("defaults", options());
+        assert_eq!(config.address, "0.0.0.0:2747".parse().unwrap());
+        assert_eq!(config.log_level, DEFAULT_LOG_LEVEL);
+
+        let config = load_with(
+            "overrides",
+            Options {
+                level: Some(LevelFilter::Trace),
+                address: Some("127.0.0.1".parse().unwrap()),
+                port: Some(9000),
+                ..options()
+            },
+        );
+        assert_eq!(config.address, "127.0.0.1:9000".parse().unwrap());
+        assert_eq!(config.log_level, LevelFilter::Trace);
+    }
+
+    fn invalid_keys(contents: &str) -> Vec<&'static str> {
+        let config: ConfigFile = toml::from_str(contents).expect("Config failed to parse");
+
+        config
+            .validate()
+            .into_iter()
+            .map(|error| match error {
+                ConfigError::Invalid { key, .. } => key,
+                _ => panic!("Unexpected error from validate(): {}", error),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn validate_ok() {
+        assert!(invalid_keys(BASE_CONFIG).is_empty());
+
+        let contents = BASE_CONFIG
+            .replace(
+                "[app]",
+                "[app]\nlog-level = \"WARNING\"\ndisabled-methods = [\"create_user\"]",
+            )
+            .replace(
+                "use-ipv6 = false",
+                "use-ipv6 = false\nconnection-log-level = \"\"",
+            );
+        let contents = format!(
+            "{}\n[rate-limits]\nlogin = 0.5\n\n[client-rate-limits]\nping = 10\n",
+            contents,
+        );
+        assert!(invalid_keys(&contents).is_empty());
+    }
+
+    #[test]
+    fn validate_all_errors() {
+        let contents = r#"
+[app]
+log-level = "loud"
+disabled-methods = ["create_user", "fly"]
+
+[network]
+use-ipv6 = false
+port = 0
+connection-log-level = "quiet"
+max-request-items = 0
+
+[data]
+database-url = ""
+revisions-dir = ""
+
+[security]
+password-blacklist-file = ""
+
+[rate-limits]
+fly = 1
+
+[client-rate-limits]
+login = 0
+"#;
+
+        assert_eq!(
+            invalid_keys(contents),
+            vec![
+                "app.log-level",
+                "network.connection-log-level",
+                "network.port",
+                "network.max-request-items",
+                "data.database-url",
+                "data.revisions-dir",
+                "app.disabled-methods",
+                "rate-limits",
+                "client-rate-limits",
+            ],
+        );
+    }
+
+    #[test]
+    fn administrators() {
+        let config = with_database_url("postgres://localhost");
+        assert!(config.administrators.is_empty());
+
+        let contents = BASE_CONFIG.replace(
+            "password-blacklist-file = \"\"",
+            "password-blacklist-file = \"\"\nadministrators = [1, 5]",
+        );
+        let config: ConfigFile = toml::from_str(&contents).expect("Config failed to parse");
+        let config = config.into_config(options());
+
+        let expected = vec![UserId::from_raw(1), UserId::from_raw(5)];
+        assert_eq!(config.administrators, expected.into_iter().collect());
+    }
+
+    #[test]
+    fn database_host() {
+        let hosts = [
+            ("postgres://localhost", "localhost"),
+            (
+                "postgres://db.example.com:5432/deepwell",
+                "db.example.com:5432",
+            ),
+            ("postgres://user:hunter2@db/deepwell", "db"),
+            ("postgresql://user:p@ss@db?sslmode=require", "db"),
+            ("postgres:///deepwell", "localhost"),
+            ("host=db user=deepwell password=hunter2", "<redacted>"),
+            ("dbname=deepwell", "<redacted>"),
+        ];
+
+        for (url, host) in &hosts {
+            assert_eq!(
+                with_database_url(url).database_host(),
+                *host,
+                "Wrong host for {}",
+                url,
+            );
+        }
+    }
+
+    #[test]
+    fn startup_log() {
+        let mut config = with_database_url("postgres://deepwell:hunter2@db/deepwell");
+        config.password_blacklist = Some(PathBuf::from("/etc/deepwell/blacklist.txt"));
+
+        let ((), messages) = capture(|| config.log());
+        let lines: Vec<_> = messages.iter().map(|(_, line)| line.as_str()).collect();
+
+        let address = format!("  Address: {}", config.address);
+        assert!(
+            lines.contains(&address.as_str()),
+            "No address in {:?}",
+            lines
+        );
+        assert!(lines.contains(&"  Database URL: <redacted>"));
+        assert!(lines.contains(&"  Password blacklist: <redacted>"));
+
+        for line in &lines {
+            assert!(!line.contains("hunter2"), "Database URL logged: {}", line);
+            assert!(
+                !line.contains("blacklist.txt"),
+                "Blacklist logged: {}",
+                line
+            );
         }
     }
 }
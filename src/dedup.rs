@@ -0,0 +1,160 @@
+/*
+ * dedup.rs
+ *
+ * deepwell-rpc - RPC server to provide database management and migrations
+ * Copyright (C) 2019-2020 Ammon Smith
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Sharing of a single backend call between identical concurrent requests.
+//!
+//! While a call for some key is in progress, any further requests for the same
+//! key wait on it and receive a copy of its result, instead of making their own.
+
+use futures::future::{BoxFuture, Shared};
+use futures::prelude::*;
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+pub struct SingleFlight<K, V> {
+    calls: Mutex<HashMap<K, Shared<BoxFuture<'static, V>>>>,
+}
+
+impl<K, V> SingleFlight<K, V>
+where
+    K: Hash + Eq + Clone + Send + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    pub fn new() -> Self {
+        SingleFlight {
+            calls: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Runs `call`, unless one for the same key is already in progress,
+    /// in which case that one's result is used instead.
+    pub fn run<F>(self: &Arc<Self>, key: K, call: F) -> BoxFuture<'static, V>
+    where
+        F: Future<Output = V> + Send + 'static,
+    {
+        let mut calls = self.calls.lock().expect("Single-flight lock poisoned");
+
+        if let Some(shared) = calls.get(&key) {
+            trace!("Joining call already in progress");
+            return shared.clone().boxed();
+        }
+
+        let this = Arc::clone(self);
+        let finished = key.clone();
+        let shared = async move {
+            let value = call.await;
+
+            this.calls
+                .lock()
+                .expect("Single-flight lock poisoned")
+                .remove(&finished);
+
+            value
+        }
+        .boxed()
+        .shared();
+
+        calls.insert(key, shared.clone());
+        shared.boxed()
+    }
+}
+
+impl<K, V> Default for SingleFlight<K, V>
+where
+    K: Hash + Eq + Clone + Send + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> Debug for SingleFlight<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SingleFlight")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::channel::oneshot;
+    use futures::executor::block_on;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn counted<V>(calls: &Arc<AtomicUsize>, value: V) -> impl Future<Output = V> {
+        let calls = Arc::clone(calls);
+
+        async move {
+            calls.fetch_add(1, Ordering::SeqCst);
+            value
+        }
+    }
+
+    #[test]
+    fn shared() {
+        let flight = Arc::new(SingleFlight::new());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let (send, recv) = oneshot::channel();
+
+        let counter = Arc::clone(&calls);
+        let first = flight.run("key", async move {
+            counter.fetch_add(1, Ordering::SeqCst);
+            recv.await.expect("Sender dropped")
+        });
+
+        // Joins the first call, which has not finished yet
+        let second = flight.run("key", counted(&calls, 0));
+
+        send.send(5).expect("Receiver dropped");
+
+        let (first, second) = block_on(future::join(first, second));
+        assert_eq!(first, 5);
+        assert_eq!(second, 5);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn separate_keys() {
+        let flight = Arc::new(SingleFlight::new());
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let first = flight.run("a", counted(&calls, 1));
+        let second = flight.run("b", counted(&calls, 2));
+
+        let (first, second) = block_on(future::join(first, second));
+        assert_eq!(first, 1);
+        assert_eq!(second, 2);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn finished_calls_not_reused() {
+        let flight = Arc::new(SingleFlight::new());
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        assert_eq!(block_on(flight.run("key", counted(&calls, 1))), 1);
+        assert_eq!(block_on(flight.run("key", counted(&calls, 2))), 2);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,239 @@
+/*
+ * events.rs
+ *
+ * deepwell-rpc - RPC server to provide database management and migrations
+ * Copyright (C) 2019-2020 Ammon Smith
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Buffer of recent events for subscribers to long-poll.
+//!
+//! Each event is given an increasing sequence number, which subscribers
+//! pass back as a cursor to receive only the events after it. Only the most
+//! recent events are kept, so a subscriber which falls too far behind will
+//! miss some.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::time::timeout;
+
+const MAX_BUFFERED_EVENTS: usize = 1024;
+
+#[derive(Debug)]
+struct Buffer<T> {
+    latest: u64,
+    events: VecDeque<(u64, T)>,
+}
+
+#[derive(Debug)]
+pub struct EventHub<T> {
+    buffer: Mutex<Buffer<T>>,
+    notify: watch::Sender<u64>,
+    watch: watch::Receiver<u64>,
+}
+
+impl<T: Clone> EventHub<T> {
+    pub fn new() -> Self {
+        let (notify, watch) = watch::channel(0);
+        let buffer = Buffer {
+            latest: 0,
+            events: VecDeque::new(),
+        };
+
+        EventHub {
+            buffer: Mutex::new(buffer),
+            notify,
+            watch,
+        }
+    }
+
+    pub fn publish(&self, event: T) {
+        let latest = {
+            let mut buffer = self.buffer.lock().expect("Event buffer lock poisoned");
+            buffer.latest += 1;
+
+            let sequence = buffer.latest;
+            buffer.events.push_back((sequence, event));
+
+            if buffer.events.len() > MAX_BUFFERED_EVENTS {
+                buffer.events.pop_front();
+            }
+
+            sequence
+        };
+
+        // Can't fail, we hold a receiver ourselves
+        let _ = self.notify.broadcast(latest);
+    }
+
+    /// Returns the latest sequence number, and any matching events after the cursor.
+    /// If there is no cursor, only the sequence number is returned.
+    fn since<F>(&self, cursor: Option<u64>, filter: &F) -> (u64, Vec<T>)
+    where
+        F: Fn(&T) -> bool,
+    {
+        let buffer = self.buffer.lock().expect("Event buffer lock poisoned");
+        let events = match cursor {
+            Some(cursor) => buffer
+                .events
+                .iter()
+                .filter(|(sequence, event)| *sequence > cursor && filter(event))
+                .map(|(_, event)| event.clone())
+                .collect(),
+            None => Vec::new(),
+        };
+
+        (buffer.latest, events)
+    }
+
+    /// Waits for events after the cursor which match the filter, for at most `wait`.
+    /// Returns the new cursor, and the events, which may be empty.
+    ///
+    /// Without a cursor, returns the latest sequence number at once, with no events.
+    pub async fn poll<F>(&self, cursor: Option<u64>, wait: Duration, filter: F) -> (u64, Vec<T>)
+    where
+        F: Fn(&T) -> bool,
+    {
+        let mut cursor = match cursor {
+            Some(cursor) => cursor,
+            None => return self.since(None, &filter),
+        };
+
+        let deadline = Instant::now() + wait;
+        let mut watch = self.watch.clone();
+
+        loop {
+            let (latest, events) = self.since(Some(cursor), &filter);
+            if !events.is_empty() {
+                return (latest, events);
+            }
+
+            cursor = latest;
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match timeout(remaining, watch.recv()).await {
+                Ok(Some(_)) => continue,
+                _ => return (latest, events),
+            }
+        }
+    }
+}
+
+impl<T: Clone> Default for EventHub<T> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::time::delay_for;
+
+    fn everything(_: &u32) -> bool {
+        true
+    }
+
+    #[test]
+    fn cursor() {
+        let hub = EventHub::new();
+        hub.publish(10);
+        hub.publish(20);
+        hub.publish(30);
+
+        assert_eq!(hub.since(None, &everything), (3, vec![]));
+        assert_eq!(hub.since(Some(0), &everything), (3, vec![10, 20, 30]));
+        assert_eq!(hub.since(Some(1), &everything), (3, vec![20, 30]));
+        assert_eq!(hub.since(Some(3), &everything), (3, vec![]));
+    }
+
+    #[test]
+    fn filter() {
+        let hub = EventHub::new();
+        for event in 1..=6 {
+            hub.publish(event);
+        }
+
+        let (latest, events) = hub.since(Some(0), &|event: &u32| *event > 3);
+        assert_eq!(latest, 6);
+        assert_eq!(events, vec![4, 5, 6]);
+    }
+
+    #[test]
+    fn overflow() {
+        let hub = EventHub::new();
+        let total = MAX_BUFFERED_EVENTS as u32 + 10;
+        for event in 1..=total {
+            hub.publish(event);
+        }
+
+        // The oldest events were dropped, the sequence number keeps counting
+        let (latest, events) = hub.since(Some(0), &everything);
+        assert_eq!(latest, u64::from(total));
+        assert_eq!(events.len(), MAX_BUFFERED_EVENTS);
+        assert_eq!(events.first(), Some(&11));
+        assert_eq!(events.last(), Some(&total));
+    }
+
+    #[tokio::test]
+    async fn poll_timeout() {
+        let hub = EventHub::<u32>::new();
+        hub.publish(1);
+
+        let (latest, events) = hub
+            .poll(Some(1), Duration::from_millis(50), everything)
+            .await;
+
+        assert_eq!(latest, 1);
+        assert!(events.is_empty());
+    }
+
+    #[tokio::test]
+    async fn poll_wakes_on_publish() {
+        let hub = Arc::new(EventHub::new());
+        let publisher = Arc::clone(&hub);
+
+        tokio::spawn(async move {
+            delay_for(Duration::from_millis(50)).await;
+            publisher.publish(7);
+        });
+
+        let (latest, events) = hub.poll(Some(0), Duration::from_secs(10), everything).await;
+        assert_eq!(latest, 1);
+        assert_eq!(events, vec![7]);
+    }
+
+    #[tokio::test]
+    async fn poll_without_cursor() {
+        let hub = EventHub::<u32>::new();
+        hub.publish(1);
+        hub.publish(2);
+
+        // Returns at once rather than waiting out the full minute
+        let (latest, events) = timeout(
+            Duration::from_secs(5),
+            hub.poll(None, Duration::from_secs(60), everything),
+        )
+        .await
+        .expect("Poll without a cursor waited");
+
+        assert_eq!(latest, 2);
+        assert!(events.is_empty());
+    }
+}
@@ -22,7 +22,9 @@
 
 #![forbid(unsafe_code)]
 
+extern crate bytes;
 extern crate deepwell_core;
+
 extern crate futures;
 
 #[macro_use]
@@ -32,12 +34,76 @@ extern crate tarpc;
 extern crate tokio;
 extern crate tokio_serde;
 
+#[cfg(any(test, feature = "mock"))]
+extern crate deepwell;
+
+#[cfg(any(test, feature = "mock"))]
+extern crate ref_map;
+
+#[cfg(any(test, feature = "mock"))]
+#[macro_use]
+extern crate serde;
+
+#[cfg(any(test, feature = "mock"))]
+#[macro_use]
+extern crate str_macro;
+
+#[cfg(any(test, feature = "mock"))]
+extern crate unicode_normalization;
+
 mod api;
+mod bucket;
 mod client;
+#[cfg(test)]
+mod log_capture;
+mod stats;
+mod transport;
+
+// The server, so clients can be tested against it with a mock backend.
+// Parts only the server binary uses, such as the real backend, are unused here.
+#[cfg(any(test, feature = "mock"))]
+#[allow(dead_code)]
+mod async_deepwell;
+#[cfg(any(test, feature = "mock"))]
+mod catch;
+#[cfg(any(test, feature = "mock"))]
+#[allow(dead_code)]
+mod config;
+#[cfg(any(test, feature = "mock"))]
+mod dedup;
+#[cfg(any(test, feature = "mock"))]
+mod events;
+#[cfg(any(test, feature = "mock"))]
+#[allow(dead_code)]
+mod mock;
+#[cfg(any(test, feature = "mock"))]
+mod payload;
+#[cfg(any(test, feature = "mock"))]
+mod ratelimit;
+#[cfg(any(test, feature = "mock"))]
+#[allow(dead_code)]
+mod server;
 
-pub use self::api::{Deepwell as Api, PROTOCOL_VERSION};
-pub use self::client::Client;
+pub use self::api::{
+    BatchLimits, Deepwell as Api, DeepwellClient as RawClient, EventBatch, NewUser,
+    PageContentsResult, ReadinessInfo, ServerError, SessionInvalidated, UptimeInfo,
+    PROTOCOL_VERSION,
+};
+pub use self::client::{Client, ClientBuilder, ClockSkew, WarmUpInfo};
+pub use self::stats::{ClientStats, MethodStats};
+pub use self::transport::{JsonTransport, TransportFactory};
 pub use deepwell_core::error::SendableError;
+pub use tarpc::context::Context;
+pub use tarpc::trace::TraceId;
+
+#[cfg(any(test, feature = "mock"))]
+pub use self::config::Config;
+
+#[cfg(any(test, feature = "mock"))]
+pub use self::mock::{MockDeepwell, MockHandle, MockServer};
+
+#[cfg(any(test, feature = "mock"))]
+pub use self::server::Server;
 
 pub type StdResult<T, E> = std::result::Result<T, E>;
 pub type Result<T> = StdResult<T, SendableError>;
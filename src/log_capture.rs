@@ -0,0 +1,90 @@
+/*
+ * log_capture.rs
+ *
+ * deepwell-rpc - RPC server to provide database management and migrations
+ * Copyright (C) 2019-2020 Ammon Smith
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Records log messages in tests, so they can be checked.
+//!
+//! Messages are kept per thread, since tests run in parallel. Tokio tests run
+//! spawned tasks on the test's own thread, so this includes a `MockServer`'s.
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::cell::RefCell;
+use std::future::Future;
+use std::sync::Once;
+
+thread_local! {
+    static MESSAGES: RefCell<Vec<(Level, String)>> = const { RefCell::new(Vec::new()) };
+}
+
+struct CaptureLogger;
+
+impl Log for CaptureLogger {
+    #[inline]
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let message = record.args().to_string();
+        MESSAGES.with(|messages| messages.borrow_mut().push((record.level(), message)));
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: CaptureLogger = CaptureLogger;
+static INIT_LOGGER: Once = Once::new();
+
+fn start() {
+    INIT_LOGGER.call_once(|| {
+        log::set_logger(&LOGGER).expect("Logger already set");
+        log::set_max_level(LevelFilter::Trace);
+    });
+
+    MESSAGES.with(|messages| messages.borrow_mut().clear());
+}
+
+fn finish() -> Vec<(Level, String)> {
+    MESSAGES.with(|messages| messages.replace(Vec::new()))
+}
+
+/// Runs `f`, returning its result and the messages logged by this thread meanwhile.
+pub fn capture<F: FnOnce() -> R, R>(f: F) -> (R, Vec<(Level, String)>) {
+    start();
+    let result = f();
+
+    (result, finish())
+}
+
+/// Like `capture()`, for a future.
+pub async fn capture_async<F: Future>(future: F) -> (F::Output, Vec<(Level, String)>) {
+    start();
+    let result = future.await;
+
+    (result, finish())
+}
+
+/// Returns only the messages at warning level or above.
+pub fn warnings(messages: &[(Level, String)]) -> Vec<&str> {
+    messages
+        .iter()
+        .filter(|(level, _)| *level <= Level::Warn)
+        .map(|(_, message)| message.as_str())
+        .collect()
+}
@@ -23,6 +23,7 @@
 #![forbid(unsafe_code)]
 
 extern crate async_std;
+extern crate bytes;
 extern crate color_backtrace;
 extern crate deepwell;
 extern crate deepwell_core;
@@ -37,23 +38,52 @@ extern crate ref_map;
 
 #[macro_use]
 extern crate serde;
+extern crate serde_json;
 
 #[macro_use]
 extern crate str_macro;
 extern crate tarpc;
 extern crate tokio;
 extern crate tokio_serde;
+extern crate unicode_normalization;
 
 mod api;
 mod async_deepwell;
+mod bucket;
+mod catch;
 mod config;
+mod dedup;
+mod events;
+#[cfg(test)]
+#[allow(dead_code)]
+mod log_capture;
+mod payload;
+mod ratelimit;
 mod server;
+mod transport;
+
+// Only the backend is used here, the rest is for tests
+#[cfg(any(test, feature = "testing"))]
+#[allow(dead_code)]
+mod mock;
+
+#[cfg(unix)]
+mod reload;
 
 use self::async_deepwell::*;
 use self::config::Config;
 use self::server::Server;
+use self::transport::JsonTransport;
+use futures::future::{self, Either, FutureExt, TryFutureExt};
+use log::LevelFilter;
 use ref_map::*;
-use std::io;
+use std::any::Any;
+use std::panic::AssertUnwindSafe;
+use std::{env, io, process};
+use tokio::signal;
+
+#[cfg(feature = "testing")]
+use self::mock::{Backend, MockDeepwell};
 
 pub use deepwell::{Config as DeepwellConfig, Server as DeepwellServer};
 pub use deepwell_core::error::SendableError;
@@ -61,38 +91,172 @@ pub use deepwell_core::error::SendableError;
 pub type StdResult<T, E> = std::result::Result<T, E>;
 pub type Result<T> = StdResult<T, SendableError>;
 
+/// Why the server stopped, other than being interrupted.
+#[derive(Debug)]
+enum Failure {
+    Rpc(io::Error),
+    BackendExited,
+    BackendPanicked(Box<dyn Any + Send>),
+}
+
 #[tokio::main]
 async fn main() -> io::Result<()> {
     color_backtrace::install();
 
-    let Config {
-        address,
-        log_level,
-        database_url,
-        revisions_dir,
-        password_blacklist,
-    } = Config::parse_args();
+    let config = Config::parse_args();
+    let address = config.address;
 
-    pretty_env_logger::formatted_builder()
-        .filter_level(log_level)
-        .init();
+    // Let this server and DEEPWELL through the logger itself, so the level can be
+    // changed at runtime. Other crates, such as tarpc, are very noisy when tracing,
+    // so they stay at info unless RUST_LOG says otherwise.
+    let mut logger = pretty_env_logger::formatted_builder();
+    logger.filter_level(LevelFilter::Info);
 
-    debug!("Building DEEPWELL server configuration");
-    let config = DeepwellConfig {
-        database_url: &database_url,
-        revisions_dir,
-        password_blacklist: password_blacklist.ref_map(|p| p.as_path()),
+    for module in &[module_path!(), "deepwell", "deepwell_core"] {
+        logger.filter_module(module, LevelFilter::Trace);
+    }
+
+    if let Ok(filters) = env::var("RUST_LOG") {
+        logger.parse_filters(&filters);
+    }
+
+    logger.init();
+
+    log::set_max_level(config.log_level);
+
+    config.log();
+
+    #[cfg(feature = "testing")]
+    let mut deepwell = if config.mock_backend {
+        warn!("Using mock backend, data is only kept in memory and some requests will fail");
+        Backend::Mock(MockDeepwell::new())
+    } else {
+        Backend::Real(start_deepwell(&config))
     };
 
-    info!("Initializing DEEPWELL server");
-    let deepwell_server = DeepwellServer::new(config).expect("Unable to start DEEPWELL server");
+    #[cfg(not(feature = "testing"))]
+    let mut deepwell = start_deepwell(&config);
 
-    let mut deepwell = AsyncDeepwell::new(deepwell_server);
     let send = deepwell.sender();
 
     info!("Initializing RPC server on {}", address);
-    let rpc = Server::init(send);
+    let server = Server::init(send, &config);
+
+    #[cfg(unix)]
+    tokio::spawn(reload::on_hangup(server.clone(), config.clone()));
+
+    // Run both in parallel, stopping whichever is left if the other exits
+    let result = {
+        let rpc = server.run(address, JsonTransport).map_err(Failure::Rpc);
+        let backend = async {
+            // Requests would wait forever on a dead backend, so shut down instead
+            match AssertUnwindSafe(deepwell.run()).catch_unwind().await {
+                Ok(()) => Failure::BackendExited,
+                Err(payload) => Failure::BackendPanicked(payload),
+            }
+        };
+        let interrupt = signal::ctrl_c();
+        pin_mut!(rpc, backend, interrupt);
+
+        let running = future::select(rpc, backend);
+
+        match future::select(running, interrupt).await {
+            Either::Left((Either::Left((result, _)), _)) => result,
+            Either::Left((Either::Right((error, _)), _)) => Err(error),
+            Either::Right((Err(error), _)) => Err(Failure::Rpc(error)),
+            Either::Right((Ok(()), running)) => {
+                info!("Interrupted, waiting for requests in progress to finish");
+
+                // Keep serving while draining, so in-flight requests can complete
+                let drain = server.drain(config.shutdown_timeout);
+                pin_mut!(drain);
+
+                match future::select(running, drain).await {
+                    Either::Left((Either::Left((result, _)), _)) => result,
+                    Either::Left((Either::Right((error, _)), _)) => Err(error),
+                    Either::Right((0, _)) => Ok(()),
+                    Either::Right((abandoned, _)) => {
+                        warn!("Shutdown timed out, abandoning {} request(s)", abandoned);
+                        Ok(())
+                    }
+                }
+            }
+        }
+    };
+
+    // Release database connections before exiting
+    drop(deepwell);
+
+    match result {
+        Ok(()) => (),
+        Err(Failure::Rpc(error)) => {
+            error!("Unable to run RPC server on {}: {}", address, error);
+            process::exit(1);
+        }
+        Err(Failure::BackendExited) => {
+            error!("DEEPWELL backend loop exited, stopping RPC server");
+            process::exit(1);
+        }
+        Err(Failure::BackendPanicked(payload)) => {
+            error!(
+                "DEEPWELL backend panicked, stopping RPC server: {}",
+                panic_message(&*payload),
+            );
+            process::exit(1);
+        }
+    }
+
+    info!("RPC server stopped");
+    Ok(())
+}
+
+/// Gets the message a panic was raised with, if it has one.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "<no message>"
+    }
+}
+
+fn start_deepwell(config: &Config) -> AsyncDeepwell {
+    debug!("Building DEEPWELL server configuration");
+    let deepwell_config = DeepwellConfig {
+        database_url: &config.database_url,
+        revisions_dir: config.revisions_dir.clone(),
+        password_blacklist: config.password_blacklist.ref_map(|p| p.as_path()),
+    };
+
+    info!("Initializing DEEPWELL server");
+    match DeepwellServer::new(deepwell_config) {
+        Ok(server) => AsyncDeepwell::new(server),
+        Err(error) => {
+            error!(
+                "Unable to start DEEPWELL server with database at {}: {}",
+                config.database_host(),
+                error,
+            );
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::panic;
+
+    #[test]
+    fn panic_messages() {
+        let payload = panic::catch_unwind(|| panic!("backend broke")).unwrap_err();
+        assert_eq!(panic_message(&*payload), "backend broke");
+
+        let payload = panic::catch_unwind(|| panic!("lost {} requests", 3)).unwrap_err();
+        assert_eq!(panic_message(&*payload), "lost 3 requests");
 
-    // Run both in parallel, return RPC status at end
-    join!(rpc.run(address), deepwell.run()).0
+        let payload = panic::catch_unwind(|| panic::panic_any(7)).unwrap_err();
+        assert_eq!(panic_message(&*payload), "<no message>");
+    }
 }
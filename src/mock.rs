@@ -0,0 +1,627 @@
+/*
+ * mock.rs
+ *
+ * deepwell-rpc - RPC server to provide database management and migrations
+ * Copyright (C) 2019-2020 Ammon Smith
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Stand-in backend for exercising the RPC protocol without a database.
+//!
+//! Only built for tests, or with the `mock` feature. Users, sessions, and pages
+//! are kept in memory, and behave like DEEPWELL's for the requests handled here.
+//! Every other request, such as `edit_user`, is dropped, which the server reports
+//! to the client as an error.
+//!
+//! `MockServer` runs a `Server` on a local port with this backend, so clients
+//! can be tested against it.
+
+use crate::api::PageContentsResult;
+use crate::api::{DeepwellRequest, DeepwellResponse};
+use crate::async_deepwell::{contents_tag, same_name};
+use crate::async_deepwell::{AsyncDeepwell, AsyncDeepwellRequest};
+use crate::config::Config;
+use crate::server::Server;
+use crate::transport::{JsonTransport, TransportFactory};
+
+#[cfg(test)]
+use crate::api::DeepwellClient;
+use crate::Result;
+use deepwell::Error as DeepwellError;
+use deepwell_core::prelude::*;
+use futures::channel::{mpsc, oneshot};
+use futures::prelude::*;
+use serde_json::json;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+#[cfg(test)]
+use tarpc::rpc::client::Config as RpcConfig;
+use tarpc::rpc::{ClientMessage, Response};
+#[cfg(test)]
+use tarpc::serde_transport::tcp;
+use tokio::net::TcpListener;
+
+const QUEUE_SIZE: usize = 64;
+
+type DeepwellResult<T> = std::result::Result<T, DeepwellError>;
+
+#[derive(Debug)]
+struct MockUser {
+    name: String,
+    email: String,
+    password: String,
+    verified: bool,
+}
+
+impl MockUser {
+    fn to_user(&self, user_id: UserId) -> User {
+        let user = json!({
+            "user_id": user_id,
+            "name": self.name,
+            "email": self.email,
+            "is_verified": self.verified,
+            "is_bot": false,
+            "author_page": "",
+            "website": "",
+            "about": "",
+            "gender": "",
+            "location": "",
+            "created_at": "2020-01-01T00:00:00Z",
+            "deleted_at": null,
+        });
+
+        serde_json::from_value(user).expect("Unable to build mock user")
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    users: Vec<MockUser>,
+    sessions: HashMap<SessionId, UserId>,
+    next_session_id: i64,
+    pages: HashMap<(WikiId, String), String>,
+    hang_requests: usize,
+}
+
+impl State {
+    fn user(&self, user_id: UserId) -> Option<&MockUser> {
+        let index = user_id.to_i64().checked_sub(1)?;
+
+        self.users.get(index as usize)
+    }
+
+    fn get_user(&self, user_id: UserId) -> Option<User> {
+        self.user(user_id).map(|user| user.to_user(user_id))
+    }
+
+    fn find_user<F>(&self, f: F) -> Option<UserId>
+    where
+        F: Fn(&MockUser) -> bool,
+    {
+        self.users
+            .iter()
+            .position(f)
+            .map(|index| UserId::from_raw(index as i64 + 1))
+    }
+
+    fn find_user_by_name(&self, name: &str) -> Option<UserId> {
+        self.find_user(|user| same_name(&user.name, name))
+    }
+
+    fn find_user_by_email(&self, email: &str) -> Option<UserId> {
+        self.find_user(|user| user.email.eq_ignore_ascii_case(email))
+    }
+
+    fn add_user(&mut self, name: &str, email: &str, password: &str) -> UserId {
+        self.users.push(MockUser {
+            name: str!(name),
+            email: str!(email),
+            password: str!(password),
+            verified: false,
+        });
+
+        UserId::from_raw(self.users.len() as i64)
+    }
+
+    fn create_user(&mut self, name: &str, email: &str, password: &str) -> DeepwellResult<UserId> {
+        if self.find_user_by_name(name).is_some() {
+            return Err(DeepwellError::UserNameExists);
+        }
+
+        if self.find_user_by_email(email).is_some() {
+            return Err(DeepwellError::UserEmailExists);
+        }
+
+        Ok(self.add_user(name, email, password))
+    }
+
+    fn login(&mut self, username_or_email: &str, password: &str) -> DeepwellResult<Session> {
+        let user_id = self
+            .find_user_by_name(username_or_email)
+            .or_else(|| self.find_user_by_email(username_or_email))
+            .filter(|&user_id| self.user(user_id).unwrap().password == password)
+            .ok_or(DeepwellError::AuthenticationFailed)?;
+
+        self.next_session_id += 1;
+        let session_id = SessionId::from_raw(self.next_session_id);
+        self.sessions.insert(session_id, user_id);
+
+        Ok(make_session(session_id, user_id))
+    }
+
+    fn check_session(&self, session_id: SessionId, user_id: UserId) -> DeepwellResult<()> {
+        match self.sessions.get(&session_id) {
+            Some(&owner) if owner == user_id => Ok(()),
+            _ => Err(DeepwellError::InvalidToken),
+        }
+    }
+
+    fn end_session(&mut self, session_id: SessionId, user_id: UserId) -> DeepwellResult<()> {
+        self.check_session(session_id, user_id)?;
+        self.sessions.remove(&session_id);
+
+        Ok(())
+    }
+
+    fn end_other_sessions(
+        &mut self,
+        session_id: SessionId,
+        user_id: UserId,
+    ) -> DeepwellResult<Vec<Session>> {
+        self.check_session(session_id, user_id)?;
+
+        let others: Vec<_> = self
+            .sessions
+            .iter()
+            .filter(|&(&id, &owner)| owner == user_id && id != session_id)
+            .map(|(&id, _)| id)
+            .collect();
+
+        for &id in &others {
+            self.sessions.remove(&id);
+        }
+
+        Ok(others
+            .into_iter()
+            .map(|id| make_session(id, user_id))
+            .collect())
+    }
+
+    fn get_or_create_user(
+        &mut self,
+        name: &str,
+        email: &str,
+        password: &str,
+    ) -> DeepwellResult<(UserId, bool)> {
+        let error = match self.create_user(name, email, password) {
+            Ok(user_id) => return Ok((user_id, true)),
+            Err(error) => error,
+        };
+
+        // The same check as the real backend, without making a session
+        match self.find_user_by_email(email) {
+            Some(user_id) => {
+                let user = self.user(user_id).unwrap();
+
+                if user.password == password && same_name(&user.name, name) {
+                    Ok((user_id, false))
+                } else {
+                    Err(error)
+                }
+            }
+            None => Err(error),
+        }
+    }
+
+    fn get_page_contents_if_changed(
+        &self,
+        wiki_id: WikiId,
+        slug: String,
+        known_tag: Option<u64>,
+    ) -> PageContentsResult {
+        match self.pages.get(&(wiki_id, slug)) {
+            Some(contents) => {
+                let tag = contents_tag(contents);
+
+                if known_tag == Some(tag) {
+                    PageContentsResult::NotModified
+                } else {
+                    PageContentsResult::Changed {
+                        contents: contents.clone(),
+                        tag,
+                    }
+                }
+            }
+            None => PageContentsResult::Missing,
+        }
+    }
+}
+
+fn make_session(session_id: SessionId, user_id: UserId) -> Session {
+    let session = json!({
+        "id": session_id,
+        "user_id": user_id,
+        "login_attempt_id": session_id,
+    });
+
+    serde_json::from_value(session).expect("Unable to build mock session")
+}
+
+fn sendable<T>(result: DeepwellResult<T>) -> Result<T> {
+    result.map_err(|e| e.to_sendable())
+}
+
+/// Changes the data of a running mock backend.
+#[derive(Debug, Clone)]
+pub struct MockHandle {
+    state: Arc<Mutex<State>>,
+}
+
+impl MockHandle {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("Mock state lock poisoned")
+    }
+
+    /// Adds a user, without the checks `create_user` makes.
+    pub fn add_user(&self, name: &str, email: &str, password: &str) -> UserId {
+        self.state().add_user(name, email, password)
+    }
+
+    /// Marks the user's email as verified.
+    pub fn verify_email(&self, user_id: UserId) {
+        let mut state = self.state();
+        let index = user_id.to_i64() as usize - 1;
+
+        state.users[index].verified = true;
+    }
+
+    /// Sets the contents of a page, creating it if needed.
+    pub fn set_page(&self, wiki_id: WikiId, slug: &str, contents: &str) {
+        self.state()
+            .pages
+            .insert((wiki_id, str!(slug)), str!(contents));
+    }
+
+    /// Whether the given session is still valid.
+    pub fn has_session(&self, session_id: SessionId) -> bool {
+        self.state().sessions.contains_key(&session_id)
+    }
+
+    /// Never answers the next `count` requests, as if the backend were stuck on them.
+    /// They are answered with an error when the mock is dropped.
+    pub fn hang_requests(&self, count: usize) {
+        self.state().hang_requests = count;
+    }
+}
+
+#[derive(Debug)]
+pub struct MockDeepwell {
+    state: Arc<Mutex<State>>,
+    recv: mpsc::Receiver<AsyncDeepwellRequest>,
+    send: mpsc::Sender<AsyncDeepwellRequest>,
+    hung: Vec<AsyncDeepwellRequest>,
+}
+
+impl MockDeepwell {
+    #[inline]
+    pub fn new() -> Self {
+        let (send, recv) = mpsc::channel(QUEUE_SIZE);
+
+        MockDeepwell {
+            state: Arc::new(Mutex::new(State::default())),
+            recv,
+            send,
+            hung: Vec::new(),
+        }
+    }
+
+    #[inline]
+    pub fn sender(&self) -> mpsc::Sender<AsyncDeepwellRequest> {
+        mpsc::Sender::clone(&self.send)
+    }
+
+    #[inline]
+    pub fn handle(&self) -> MockHandle {
+        MockHandle {
+            state: Arc::clone(&self.state),
+        }
+    }
+
+    pub async fn run(&mut self) {
+        while let Some(request) = self.recv.next().await {
+            self.handle_request(request);
+        }
+
+        panic!("Receiver stream exhausted");
+    }
+
+    fn handle_request(&mut self, request: AsyncDeepwellRequest) {
+        use AsyncDeepwellRequest::*;
+
+        fn send<T>(response: oneshot::Sender<DeepwellResult<T>>, result: DeepwellResult<T>) {
+            let _ = response.send(result);
+        }
+
+        let mut state = self.state.lock().expect("Mock state lock poisoned");
+
+        if state.hang_requests > 0 {
+            debug!("Holding request without answering (mock)");
+
+            state.hang_requests -= 1;
+            self.hung.push(request);
+            return;
+        }
+
+        match request {
+            Ping { response } => send(response, Ok(())),
+            TryLogin {
+                username_or_email,
+                password,
+                response,
+                ..
+            } => send(response, state.login(&username_or_email, &password)),
+            CheckSession {
+                session_id,
+                user_id,
+                response,
+            } => send(response, state.check_session(session_id, user_id)),
+            Logout {
+                session_id,
+                user_id,
+                response,
+            } => send(response, state.end_session(session_id, user_id)),
+            LogoutOthers {
+                session_id,
+                user_id,
+                response,
+            } => send(response, state.end_other_sessions(session_id, user_id)),
+            CreateUser {
+                name,
+                email,
+                password,
+                response,
+            } => send(response, state.create_user(&name, &email, &password)),
+            GetUserFromId { user_id, response } => send(response, Ok(state.get_user(user_id))),
+            GetUsersFromIds { user_ids, response } => {
+                let users = user_ids.iter().map(|&id| state.get_user(id)).collect();
+
+                send(response, Ok(users))
+            }
+            GetUserFromName { name, response } => {
+                let user = state.find_user_by_name(&name);
+
+                send(response, Ok(user.and_then(|id| state.get_user(id))))
+            }
+            GetUserFromEmail { email, response } => {
+                let user = state.find_user_by_email(&email);
+
+                send(response, Ok(user.and_then(|id| state.get_user(id))))
+            }
+            GetPageContents {
+                wiki_id,
+                slug,
+                response,
+            } => send(response, Ok(state.pages.get(&(wiki_id, slug)).cloned())),
+            IsUsernameAvailable { name, response } => {
+                send(response, Ok(state.find_user_by_name(&name).is_none()))
+            }
+            IsEmailAvailable { email, response } => {
+                send(response, Ok(state.find_user_by_email(&email).is_none()))
+            }
+            CreateUsers { users, response } => {
+                let results = users
+                    .iter()
+                    .map(|user| {
+                        sendable(state.create_user(&user.name, &user.email, &user.password))
+                    })
+                    .collect();
+
+                send(response, Ok(results))
+            }
+            LogoutSessions { sessions, response } => {
+                let results = sessions
+                    .into_iter()
+                    .map(|(session_id, user_id)| sendable(state.end_session(session_id, user_id)))
+                    .collect();
+
+                send(response, Ok(results))
+            }
+            IsEmailVerified { user_id, response } => {
+                let result = match state.user(user_id) {
+                    Some(user) => Ok(user.verified),
+                    None => Err(DeepwellError::UserNotFound),
+                };
+
+                send(response, result)
+            }
+            GetOrCreateUser {
+                name,
+                email,
+                password,
+                response,
+            } => send(response, state.get_or_create_user(&name, &email, &password)),
+            GetPageContentsIfChanged {
+                wiki_id,
+                slug,
+                known_tag,
+                response,
+            } => {
+                let result = state.get_page_contents_if_changed(wiki_id, slug, known_tag);
+
+                send(response, Ok(result))
+            }
+            _ => debug!("Dropping request unsupported by mock backend"),
+        }
+    }
+}
+
+impl Default for MockDeepwell {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A server on a local port, answered by a mock backend.
+///
+/// Both run in the background until the test's runtime shuts down.
+#[derive(Debug)]
+pub struct MockServer {
+    address: SocketAddr,
+    server: Server,
+    mock: MockHandle,
+    stop: Option<(oneshot::Sender<()>, oneshot::Receiver<()>)>,
+}
+
+impl MockServer {
+    /// Starts a server with the given configuration, ignoring its address.
+    #[inline]
+    pub async fn start(config: &Config) -> io::Result<Self> {
+        Self::start_with_transport(config, JsonTransport).await
+    }
+
+    /// Like `start()`, framing messages with the given codec instead of JSON.
+    pub async fn start_with_transport<T>(config: &Config, transport: T) -> io::Result<Self>
+    where
+        T: TransportFactory<ClientMessage<DeepwellRequest>, Response<DeepwellResponse>>,
+    {
+        let mut backend = MockDeepwell::new();
+        let mock = backend.handle();
+        let server = Server::init(backend.sender(), config);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+
+        tokio::spawn(async move { backend.run().await });
+
+        let (stop, stop_recv) = oneshot::channel();
+        let (stopped_send, stopped) = oneshot::channel();
+        let serving = server.clone();
+
+        tokio::spawn(async move {
+            // Dropping the future closes the listener and every connection
+            let serve = serving.serve(listener, transport).boxed();
+            future::select(serve, stop_recv).map(drop).await;
+            let _ = stopped_send.send(());
+        });
+
+        Ok(MockServer {
+            address,
+            server,
+            mock,
+            stop: Some((stop, stopped)),
+        })
+    }
+
+    /// Stops serving, closing the listener and every connection.
+    /// The mock backend keeps running, along with its data.
+    pub async fn stop(&mut self) {
+        if let Some((stop, stopped)) = self.stop.take() {
+            let _ = stop.send(());
+            let _ = stopped.await;
+        }
+    }
+
+    #[inline]
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    #[inline]
+    pub fn server(&self) -> &Server {
+        &self.server
+    }
+
+    #[inline]
+    pub fn mock(&self) -> &MockHandle {
+        &self.mock
+    }
+
+    /// Opens a connection without the `Client` wrapper, for tests of the server itself.
+    #[cfg(test)]
+    pub async fn connect_raw(&self) -> DeepwellClient {
+        let codec =
+            TransportFactory::<Response<DeepwellResponse>, ClientMessage<DeepwellRequest>>::codec(
+                &JsonTransport,
+            );
+        let transport = tcp::connect(&self.address, codec)
+            .await
+            .expect("Unable to connect to mock server");
+
+        DeepwellClient::new(RpcConfig::default(), transport)
+            .spawn()
+            .expect("Unable to start client")
+    }
+}
+
+/// Either the real backend, or the mock one if requested on the command line.
+#[derive(Debug)]
+pub enum Backend {
+    Real(AsyncDeepwell),
+    Mock(MockDeepwell),
+}
+
+impl Backend {
+    #[inline]
+    pub fn sender(&self) -> mpsc::Sender<AsyncDeepwellRequest> {
+        match self {
+            Backend::Real(deepwell) => deepwell.sender(),
+            Backend::Mock(mock) => mock.sender(),
+        }
+    }
+
+    pub async fn run(&mut self) {
+        match self {
+            Backend::Real(deepwell) => deepwell.run().await,
+            Backend::Mock(mock) => mock.run().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tarpc::rpc::context;
+
+    #[tokio::test]
+    async fn round_trip() {
+        let server = MockServer::start(&Config::testing()).await.unwrap();
+        let user_id = server
+            .mock()
+            .add_user("alice", "alice@example.com", "hunter2");
+
+        let mut client = server.connect_raw().await;
+
+        let pong = client.ping(context::current()).await.unwrap();
+        assert_eq!(pong, Ok(()));
+
+        let user = client
+            .get_user_from_id(context::current(), user_id)
+            .await
+            .unwrap()
+            .expect("Mock lookup failed")
+            .expect("Mock user missing");
+        assert_eq!(user.name(), "alice");
+        assert_eq!(user.email(), "alice@example.com");
+
+        let missing = client
+            .get_user_from_id(context::current(), UserId::from_raw(2))
+            .await
+            .unwrap();
+        assert_eq!(missing, Ok(None));
+    }
+}
@@ -0,0 +1,175 @@
+/*
+ * payload.rs
+ *
+ * deepwell-rpc - RPC server to provide database management and migrations
+ * Copyright (C) 2019-2020 Ammon Smith
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Trace logging of request and response payloads, for debugging the protocol.
+//!
+//! Any object key which looks like it holds a credential has its value
+//! replaced before it is logged.
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+const REDACTED: &str = "<redacted>";
+const SENSITIVE_KEYS: [&str; 3] = ["password", "secret", "token"];
+
+pub type Arguments = Map<String, Value>;
+
+/// Converts a value to JSON, substituting a note if it cannot be serialized.
+pub fn to_value<T: Serialize>(value: &T) -> Value {
+    serde_json::to_value(value)
+        .unwrap_or_else(|error| Value::String(format!("<unserializable: {}>", error)))
+}
+
+pub fn log_request(request: &str, arguments: Arguments) {
+    let mut value = Value::Object(arguments);
+    redact(&mut value);
+
+    trace!("Request payload ({}): {}", request, value);
+}
+
+pub fn log_response<T: Serialize>(request: &str, response: &T) {
+    let mut value = to_value(response);
+    redact(&mut value);
+
+    trace!("Response payload ({}): {}", request, value);
+}
+
+fn is_sensitive(key: &str) -> bool {
+    let key = key.to_ascii_lowercase();
+
+    SENSITIVE_KEYS
+        .iter()
+        .any(|sensitive| key.contains(sensitive))
+}
+
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if is_sensitive(key) {
+                    *value = Value::String(str!(REDACTED));
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::NewUser;
+    use serde_json::json;
+
+    fn redacted(mut value: Value) -> Value {
+        redact(&mut value);
+        value
+    }
+
+    #[test]
+    fn sensitive_keys() {
+        assert!(is_sensitive("password"));
+        assert!(is_sensitive("new_password"));
+        assert!(is_sensitive("Password"));
+        assert!(is_sensitive("client_secret"));
+        assert!(is_sensitive("API_TOKEN"));
+        assert!(!is_sensitive("username_or_email"));
+        assert!(!is_sensitive("session_id"));
+    }
+
+    #[test]
+    fn redact_top_level() {
+        let value = json!({
+            "username_or_email": "alice",
+            "password": "hunter2",
+        });
+
+        assert_eq!(
+            redacted(value),
+            json!({
+                "username_or_email": "alice",
+                "password": REDACTED,
+            }),
+        );
+    }
+
+    #[test]
+    fn redact_nested() {
+        let value = json!({
+            "users": [
+                { "name": "alice", "token": "abc" },
+                { "name": "bob", "details": { "secret": 42 } },
+            ],
+        });
+
+        assert_eq!(
+            redacted(value),
+            json!({
+                "users": [
+                    { "name": "alice", "token": REDACTED },
+                    { "name": "bob", "details": { "secret": REDACTED } },
+                ],
+            }),
+        );
+    }
+
+    #[test]
+    fn redact_whole_value() {
+        // Objects under a sensitive key are replaced entirely, not searched
+        let value = json!({ "password": { "old": "a", "new": "b" } });
+
+        assert_eq!(redacted(value), json!({ "password": REDACTED }));
+    }
+
+    #[test]
+    fn redact_new_users() {
+        let users = vec![NewUser {
+            name: str!("alice"),
+            email: str!("alice@example.com"),
+            password: str!("hunter2"),
+        }];
+
+        let mut arguments = Arguments::new();
+        arguments.insert(str!("users"), to_value(&users));
+
+        assert_eq!(
+            redacted(Value::Object(arguments)),
+            json!({
+                "users": [{
+                    "name": "alice",
+                    "email": "alice@example.com",
+                    "password": REDACTED,
+                }],
+            }),
+        );
+    }
+
+    #[test]
+    fn leave_scalars() {
+        assert_eq!(redacted(json!("password")), json!("password"));
+        assert_eq!(
+            redacted(json!(["token", 1, null])),
+            json!(["token", 1, null])
+        );
+    }
+}
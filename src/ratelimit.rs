@@ -0,0 +1,189 @@
+/*
+ * ratelimit.rs
+ *
+ * deepwell-rpc - RPC server to provide database management and migrations
+ * Copyright (C) 2019-2020 Ammon Smith
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Limits on how often particular methods may be called.
+//!
+//! Each method in `[rate-limits]` has a token bucket shared by all clients,
+//! and each method in `[client-rate-limits]` has one for each client address.
+//! Buckets refill at their configured rate, and can hold up to one second's
+//! worth of calls. A call must be allowed by both limits, if present.
+
+use crate::bucket::TokenBucket;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, RwLock};
+use std::time::Instant;
+
+/// Once this many client buckets exist, those which have refilled are removed.
+const MAX_CLIENT_BUCKETS: usize = 4096;
+
+type Buckets = HashMap<String, Mutex<TokenBucket>>;
+type ClientBuckets = HashMap<(String, IpAddr), TokenBucket>;
+
+#[derive(Debug, Default)]
+pub struct RateLimits {
+    buckets: RwLock<Buckets>,
+    client_rates: RwLock<HashMap<String, f64>>,
+    client_buckets: Mutex<ClientBuckets>,
+}
+
+impl RateLimits {
+    /// Creates limits from maps of method names to calls allowed per second,
+    /// across all clients and for each client respectively.
+    pub fn new(rates: &HashMap<String, f64>, client_rates: &HashMap<String, f64>) -> Self {
+        RateLimits {
+            buckets: RwLock::new(Self::buckets(rates)),
+            client_rates: RwLock::new(client_rates.clone()),
+            client_buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Replaces all the limits, such as after the configuration is reloaded.
+    /// Every bucket starts full again.
+    pub fn replace(&self, rates: &HashMap<String, f64>, client_rates: &HashMap<String, f64>) {
+        let buckets = Self::buckets(rates);
+        *self.buckets.write().expect("Rate limit lock poisoned") = buckets;
+
+        let mut current = self.client_rates.write().expect("Rate limit lock poisoned");
+        *current = client_rates.clone();
+        self.client_buckets
+            .lock()
+            .expect("Rate limit lock poisoned")
+            .clear();
+    }
+
+    fn buckets(rates: &HashMap<String, f64>) -> Buckets {
+        // Rates are checked to be positive by Config::validate()
+        rates
+            .iter()
+            .map(|(method, &rate)| {
+                let bucket = TokenBucket::new(rate, rate.max(1.0));
+                (method.clone(), Mutex::new(bucket))
+            })
+            .collect()
+    }
+
+    /// Uses up a call to the given method, returning `false` if over its limit.
+    /// Methods without a configured limit are always allowed, as are clients
+    /// without a known address.
+    pub fn check(&self, method: &str, client: Option<IpAddr>) -> bool {
+        if let Some(client) = client {
+            if !self.check_client(method, client) {
+                return false;
+            }
+        }
+
+        let buckets = self.buckets.read().expect("Rate limit lock poisoned");
+
+        match buckets.get(method) {
+            Some(bucket) => bucket.lock().expect("Rate limit lock poisoned").take(),
+            None => true,
+        }
+    }
+
+    fn check_client(&self, method: &str, client: IpAddr) -> bool {
+        let client_rates = self.client_rates.read().expect("Rate limit lock poisoned");
+        let rate = match client_rates.get(method) {
+            Some(&rate) => rate,
+            None => return true,
+        };
+
+        let mut buckets = self
+            .client_buckets
+            .lock()
+            .expect("Rate limit lock poisoned");
+
+        if buckets.len() >= MAX_CLIENT_BUCKETS {
+            // Removing a full bucket loses nothing, a new one starts full anyway
+            let now = Instant::now();
+            buckets.retain(|_, bucket| !bucket.is_full_at(now));
+        }
+
+        buckets
+            .entry((str!(method), client))
+            .or_insert_with(|| TokenBucket::new(rate, rate.max(1.0)))
+            .take()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn rates(method: &str, rate: f64) -> HashMap<String, f64> {
+        let mut rates = HashMap::new();
+        rates.insert(str!(method), rate);
+        rates
+    }
+
+    #[test]
+    fn global_limit() {
+        let limits = RateLimits::new(&rates("login", 2.0), &HashMap::new());
+        let first = Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        let second = Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+
+        assert!(limits.check("login", first));
+        assert!(limits.check("login", second));
+        assert!(!limits.check("login", first));
+        assert!(!limits.check("login", second));
+        assert!(limits.check("ping", first));
+    }
+
+    #[test]
+    fn client_limit() {
+        let limits = RateLimits::new(&HashMap::new(), &rates("login", 1.0));
+        let first = Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        let second = Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+
+        assert!(limits.check("login", first));
+        assert!(!limits.check("login", first));
+        assert!(limits.check("login", second));
+        assert!(!limits.check("login", second));
+        assert!(limits.check("ping", first));
+        assert!(limits.check("login", None));
+    }
+
+    #[test]
+    fn both_limits() {
+        let limits = RateLimits::new(&rates("login", 2.0), &rates("login", 1.0));
+        let first = Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        let second = Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+        let third = Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3)));
+
+        assert!(limits.check("login", first));
+        assert!(!limits.check("login", first));
+        assert!(limits.check("login", second));
+        assert!(!limits.check("login", third));
+    }
+
+    #[test]
+    fn replace() {
+        let limits = RateLimits::new(&HashMap::new(), &rates("login", 1.0));
+        let client = Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+
+        assert!(limits.check("login", client));
+        assert!(!limits.check("login", client));
+
+        limits.replace(&HashMap::new(), &HashMap::new());
+        assert!(limits.check("login", client));
+        assert!(limits.check("login", client));
+    }
+}
@@ -0,0 +1,101 @@
+/*
+ * reload.rs
+ *
+ * deepwell-rpc - RPC server to provide database management and migrations
+ * Copyright (C) 2019-2020 Ammon Smith
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Reloading of the configuration file on SIGHUP.
+//!
+//! Only the log level, read-only mode, and rate limits are changed on a
+//! running server. Changes to anything else are reported, but need a restart.
+
+use crate::config::Config;
+use crate::server::Server;
+use tokio::signal::unix::{signal, SignalKind};
+
+macro_rules! needs_restart {
+    ($old:expr, $new:expr, [ $($field:ident),* $(,)? ] $(,)?) => {
+        $(
+            if $old.$field != $new.$field {
+                warn!("Change to {} needs a restart to take effect", stringify!($field));
+            }
+        )*
+    };
+}
+
+/// Reloads the configuration each time the process receives SIGHUP.
+pub async fn on_hangup(server: Server, mut config: Config) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(error) => {
+            warn!(
+                "Unable to listen for SIGHUP, configuration will not be reloaded: {}",
+                error
+            );
+            return;
+        }
+    };
+
+    while let Some(()) = hangups.recv().await {
+        info!("Received SIGHUP, reloading configuration");
+
+        let new_config = match config.reload() {
+            Ok(new_config) => new_config,
+            Err(errors) => {
+                for error in &errors {
+                    error!("{}", error);
+                }
+
+                warn!("Keeping the current configuration");
+                continue;
+            }
+        };
+
+        apply(&server, &config, &new_config);
+        config = new_config;
+    }
+}
+
+fn apply(server: &Server, old: &Config, new: &Config) {
+    if old.log_level != new.log_level {
+        warn!("Changing log level to {}", new.log_level);
+        log::set_max_level(new.log_level);
+    }
+
+    server.reload(old, new);
+
+    needs_restart!(
+        old,
+        new,
+        [
+            address,
+            connection_log_level,
+            max_request_items,
+            reject_when_busy,
+            tcp_nodelay,
+            shutdown_timeout,
+            log_payloads,
+            deduplicate_reads,
+            min_client_protocol,
+            disabled_methods,
+            database_url,
+            revisions_dir,
+            password_blacklist,
+            administrators,
+        ],
+    );
+}
@@ -18,39 +18,136 @@
  * along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
 
-use crate::api::{Deepwell as DeepwellApi, PROTOCOL_VERSION};
+use crate::api::{
+    BatchLimits, Deepwell as DeepwellApi, DeepwellRequest, DeepwellResponse, EventBatch, NewUser,
+    PageContentsResult, ReadinessInfo, ServerError, SessionInvalidated, UptimeInfo,
+    PROTOCOL_VERSION,
+};
 use crate::async_deepwell::AsyncDeepwellRequest;
+use crate::catch::CatchErrors;
+use crate::config::{find_log_level, Config};
+use crate::dedup::SingleFlight;
+use crate::events::EventHub;
+use crate::payload;
+use crate::ratelimit::RateLimits;
+use crate::transport::TransportFactory;
 use crate::Result;
+use deepwell::Error as DeepwellError;
 use deepwell_core::prelude::*;
 use futures::channel::{mpsc, oneshot};
 use futures::future::{self, BoxFuture, Ready};
 use futures::prelude::*;
+use log::{Level, LevelFilter};
+use std::collections::HashSet;
 use std::io;
 use std::net::SocketAddr;
-use std::time::SystemTime;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 use tarpc::context::Context;
+use tarpc::rpc::{ClientMessage, Response};
 use tarpc::serde_transport::tcp;
 use tarpc::server::{BaseChannel, Channel};
-use tokio_serde::formats::Json;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::delay_for;
 
 // Prevent network socket exhaustion or related slowdown
 const MAX_PARALLEL_REQUESTS: usize = 16;
 
+// How long before the client's deadline to answer a long poll
+const POLL_MARGIN: Duration = Duration::from_millis(500);
+
+// How often to check whether in-flight requests have finished during shutdown
+const DRAIN_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Methods which can be disabled or rate limited in the configuration.
+pub const CONFIGURABLE_METHODS: [&str; 22] = [
+    "ping",
+    "login",
+    "logout",
+    "logout_others",
+    "check_session",
+    "poll_session_events",
+    "create_user",
+    "edit_user",
+    "get_user_from_id",
+    "get_users_from_ids",
+    "get_user_from_name",
+    "get_user_from_email",
+    "is_username_available",
+    "is_email_available",
+    "create_users",
+    "is_email_verified",
+    "get_or_create_user",
+    "get_page_contents",
+    "get_page_contents_if_changed",
+    "set_read_only",
+    "logout_sessions",
+    "set_log_level",
+];
+
 macro_rules! forward_impl {
-    ($self:expr, $request:expr, $recv:expr) => {{
+    ($self:expr, $ctx:expr, $name:expr, $request:expr, $recv:expr) => {{
+        let deadline = $ctx.deadline;
+        let trace_id = *$ctx.trace_id();
+
         let fut = async move {
-            // Send to process
-            $self
-                .channel
-                .send($request)
-                .await
-                .expect("Deepwell server channel closed");
-
-            // Wait for result to arrive
-            $recv
-                .await
-                .expect("Oneshot closed before result")
-                .map_err(|e| e.to_sendable())
+            // Counted before checking, so drain() can't miss a request which got past the check.
+            // SeqCst orders this against drain() setting the flag and then reading the count.
+            let _in_flight = InFlight::new(&$self.in_flight);
+
+            // Don't start new work while shutting down
+            if $self.draining.load(Ordering::SeqCst) {
+                return Err(ServerError::ShuttingDown.to_sendable());
+            }
+
+            // Don't bother the backend if the client has already given up
+            if SystemTime::now() >= deadline {
+                warn!(
+                    "Deadline passed before {} [{}] could be processed",
+                    $name, trace_id,
+                );
+
+                return Err(ServerError::DeadlinePassed.to_sendable());
+            }
+
+            // Send to process, or give up if the queue is full and we're not waiting
+            if $self.reject_when_busy {
+                if let Err(error) = $self.channel.try_send($request) {
+                    if !error.is_full() {
+                        panic!("Deepwell server channel closed");
+                    }
+
+                    warn!(
+                        "Rejecting {} [{}] as the backend queue is full",
+                        $name, trace_id,
+                    );
+
+                    return Err(ServerError::ServerBusy.to_sendable());
+                }
+            } else {
+                $self
+                    .channel
+                    .send($request)
+                    .await
+                    .expect("Deepwell server channel closed");
+            }
+
+            // Wait for result to arrive, the backend may drop requests it can't handle
+            let result = match $recv.await {
+                Ok(result) => result.map_err(|e| e.to_sendable()),
+                Err(_) => {
+                    warn!("Backend dropped {} [{}] without a result", $name, trace_id);
+
+                    Err(DeepwellError::StaticMsg("request not handled by backend").to_sendable())
+                }
+            };
+
+            if $self.log_payloads {
+                payload::log_response($name, &result);
+            }
+
+            result
         };
 
         fut.boxed()
@@ -58,16 +155,25 @@ macro_rules! forward_impl {
 }
 
 macro_rules! forward {
-    ($self:expr, $request:tt, [ $($field:ident),* ] , ) => {
-        forward!($self, $request, [ $($field),* ])
+    ($self:expr, $ctx:expr, $request:tt, [ $($field:ident),* ] , ) => {
+        forward!($self, $ctx, $request, [ $($field),* ])
     };
 
-    ($self:expr, $request:tt, [ $($field:ident),* , ] ) => {
-        forward!($self, $request, [ $($field),* ])
+    ($self:expr, $ctx:expr, $request:tt, [ $($field:ident),* , ] ) => {
+        forward!($self, $ctx, $request, [ $($field),* ])
     };
 
     // Request with listed fields (local variables)
-    ($self:expr, $request:tt, [ $($field:ident),* ] ) => {{
+    ($self:expr, $ctx:expr, $request:tt, [ $($field:ident),* ] ) => {{
+        if $self.log_payloads {
+            let mut arguments = payload::Arguments::new();
+            $(
+                arguments.insert(str!(stringify!($field)), payload::to_value(&$field));
+            )*
+
+            payload::log_request(stringify!($request), arguments);
+        }
+
         let (send, recv) = oneshot::channel();
 
         let request = AsyncDeepwellRequest::$request {
@@ -75,45 +181,190 @@ macro_rules! forward {
             response: send,
         };
 
-        forward_impl!($self, request, recv)
+        forward_impl!($self, $ctx, stringify!($request), request, recv)
     }};
 
     // Empty request
-    ($self:expr, $request:tt) => {{
+    ($self:expr, $ctx:expr, $request:tt) => {{
+        if $self.log_payloads {
+            payload::log_request(stringify!($request), payload::Arguments::new());
+        }
+
         let (send, recv) = oneshot::channel();
 
         let request = AsyncDeepwellRequest::$request {
             response: send,
         };
 
-        forward_impl!($self, request, recv)
+        forward_impl!($self, $ctx, stringify!($request), request, recv)
+    }};
+}
+
+// Reject methods which modify data while in read-only mode
+macro_rules! writable {
+    ($self:expr) => {
+        if $self.read_only.load(Ordering::Acquire) {
+            warn!("Rejecting write while in read-only mode");
+
+            let error = ServerError::ReadOnly.to_sendable();
+            return future::err(error).boxed();
+        }
+    };
+}
+
+// Reject list arguments longer than the configured maximum
+macro_rules! bounded {
+    ($self:expr, $items:expr) => {
+        if $items.len() > $self.max_request_items {
+            warn!(
+                "Rejecting request with {} items (maximum {})",
+                $items.len(),
+                $self.max_request_items,
+            );
+
+            let error = ServerError::RequestTooLarge.to_sendable();
+            return future::err(error).boxed();
+        }
+    };
+}
+
+// Reject calls to a disabled method, or to a method over its configured rate limit
+macro_rules! throttle {
+    ($self:expr, $method:expr) => {
+        debug_assert!(CONFIGURABLE_METHODS.contains(&$method));
+
+        if $self.disabled_methods.contains($method) {
+            warn!("Rejecting call to disabled method {}", $method);
+
+            let error = ServerError::MethodDisabled.to_sendable();
+            return future::err(error).boxed();
+        }
+
+        let client = $self.peer_addr.map(|addr| addr.ip());
+        if !$self.rate_limits.check($method, client) {
+            warn!("Rejecting call to {} over its rate limit", $method);
+
+            let error = ServerError::RateLimited.to_sendable();
+            return future::err(error).boxed();
+        }
+    };
+}
+
+type UserReads = SingleFlight<UserId, Result<Option<User>>>;
+type PageReads = SingleFlight<(WikiId, String), Result<Option<String>>>;
+
+// Check the session, then that its user is a configured administrator
+macro_rules! check_admin {
+    ($self:expr, $ctx:expr, $session_id:expr, $user_id:expr) => {{
+        let session_id = $session_id;
+        let user_id = $user_id;
+        let is_admin = $self.administrators.contains(&user_id);
+        let mut server = $self.clone();
+        let check = forward!(server, $ctx, CheckSession, [session_id, user_id]);
+
+        async move {
+            check.await?;
+
+            if is_admin {
+                Ok(())
+            } else {
+                warn!("Rejecting administration call from user ID {}", user_id);
+
+                Err(ServerError::NotAdmin.to_sendable())
+            }
+        }
     }};
 }
 
 #[derive(Debug, Clone)]
 pub struct Server {
     channel: mpsc::Sender<AsyncDeepwellRequest>,
+    connection_log_level: LevelFilter,
+    max_request_items: usize,
+    reject_when_busy: bool,
+    tcp_nodelay: bool,
+    min_client_protocol: Option<u32>,
+    log_payloads: bool,
+    read_only: Arc<AtomicBool>,
+    rate_limits: Arc<RateLimits>,
+    disabled_methods: Arc<HashSet<String>>,
+    administrators: Arc<HashSet<UserId>>,
+    session_events: Arc<EventHub<SessionInvalidated>>,
+    in_flight: Arc<AtomicUsize>,
+    draining: Arc<AtomicBool>,
+    peer_addr: Option<SocketAddr>,
+    user_reads: Option<Arc<UserReads>>,
+    page_reads: Option<Arc<PageReads>>,
+    start_time: f64,
+    started: Instant,
 }
 
 impl Server {
     #[inline]
-    pub fn init(channel: mpsc::Sender<AsyncDeepwellRequest>) -> Self {
-        Self { channel }
+    pub fn init(channel: mpsc::Sender<AsyncDeepwellRequest>, config: &Config) -> Self {
+        let (user_reads, page_reads) = if config.deduplicate_reads {
+            (
+                Some(Arc::new(SingleFlight::new())),
+                Some(Arc::new(SingleFlight::new())),
+            )
+        } else {
+            (None, None)
+        };
+
+        Self {
+            channel,
+            connection_log_level: config.connection_log_level,
+            max_request_items: config.max_request_items,
+            reject_when_busy: config.reject_when_busy,
+            tcp_nodelay: config.tcp_nodelay,
+            min_client_protocol: config.min_client_protocol,
+            log_payloads: config.log_payloads,
+            read_only: Arc::new(AtomicBool::new(config.read_only)),
+            rate_limits: Arc::new(RateLimits::new(
+                &config.rate_limits,
+                &config.client_rate_limits,
+            )),
+            disabled_methods: Arc::new(config.disabled_methods.clone()),
+            administrators: Arc::new(config.administrators.clone()),
+            session_events: Arc::new(EventHub::new()),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            draining: Arc::new(AtomicBool::new(false)),
+            peer_addr: None,
+            user_reads,
+            page_reads,
+            start_time: unix_time(SystemTime::now()),
+            started: Instant::now(),
+        }
+    }
+
+    pub async fn run<T>(&self, address: SocketAddr, transport: T) -> io::Result<()>
+    where
+        T: TransportFactory<ClientMessage<DeepwellRequest>, Response<DeepwellResponse>>,
+    {
+        let listener = TcpListener::bind(address).await?;
+
+        self.serve(listener, transport).await;
+
+        Ok(())
     }
 
-    pub async fn run(&self, address: SocketAddr) -> io::Result<()> {
-        tcp::listen(&address, Json::default)
-            .await?
+    /// Accepts connections on an already bound listener, such as one on a random port.
+    pub async fn serve<T>(&self, mut listener: TcpListener, transport: T)
+    where
+        T: TransportFactory<ClientMessage<DeepwellRequest>, Response<DeepwellResponse>>,
+    {
+        let connection_log_level = self.connection_log_level.to_level();
+        let tcp_nodelay = self.tcp_nodelay;
+
+        listener
+            .incoming()
             // Log requests
             .filter_map(|conn| {
                 async move {
                     match conn {
                         // Note incoming connection
                         Ok(conn) => {
-                            match conn.peer_addr() {
-                                Ok(addr) => info!("Accepted connection from {}", addr),
-                                Err(error) => warn!("Unable to get peer address: {}", error),
-                            }
+                            accepted(&conn, connection_log_level, tcp_nodelay);
 
                             Some(conn)
                         }
@@ -127,16 +378,72 @@ impl Server {
                 }
             })
             // Create and fulfill channels for each request
-            .map(BaseChannel::with_defaults)
-            .map(|chan| {
-                let resp = self.clone().serve();
-                chan.respond_with(resp).execute()
+            //
+            // The JSON codec decodes whole frames before tarpc sees a request ID,
+            // so a malformed frame ends the channel for that connection, including
+            // any requests still in flight on it. Other connections are unaffected.
+            .map(|conn| {
+                // Each connection gets its own copy, which knows who it is serving
+                let mut server = self.clone();
+                server.peer_addr = conn.peer_addr().ok();
+
+                let conn = tcp::new(conn, CatchErrors(transport.codec()));
+                let chan = BaseChannel::with_defaults(conn);
+                chan.respond_with(server.serve()).execute()
             })
             .buffer_unordered(MAX_PARALLEL_REQUESTS)
             .for_each(|_| async {})
             .await;
+    }
 
-        Ok(())
+    /// Rejects new requests, and waits for those in progress to finish for at most `wait`.
+    /// Returns the number of requests which were still in progress.
+    pub async fn drain(&self, wait: Duration) -> usize {
+        self.draining.store(true, Ordering::SeqCst);
+
+        let deadline = Instant::now() + wait;
+
+        loop {
+            let in_flight = self.in_flight.load(Ordering::SeqCst);
+            if in_flight == 0 || Instant::now() >= deadline {
+                return in_flight;
+            }
+
+            delay_for(DRAIN_INTERVAL).await;
+        }
+    }
+
+    /// Applies the settings which changed in a reloaded configuration and can
+    /// be changed while running. Shared by every connection's copy of the server.
+    pub fn reload(&self, old: &Config, new: &Config) {
+        if old.read_only != new.read_only {
+            warn!("Setting read-only mode to {}", new.read_only);
+            self.read_only.store(new.read_only, Ordering::Release);
+        }
+
+        if old.rate_limits != new.rate_limits || old.client_rate_limits != new.client_rate_limits {
+            info!("Replacing rate limits");
+            self.rate_limits
+                .replace(&new.rate_limits, &new.client_rate_limits);
+        }
+    }
+}
+
+/// Counts a request as in progress until it is dropped.
+#[derive(Debug)]
+struct InFlight<'a>(&'a AtomicUsize);
+
+impl<'a> InFlight<'a> {
+    fn new(counter: &'a AtomicUsize) -> Self {
+        counter.fetch_add(1, Ordering::SeqCst);
+
+        InFlight(counter)
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
     }
 }
 
@@ -146,33 +453,127 @@ impl DeepwellApi for Server {
     type ProtocolFut = Ready<String>;
 
     #[inline]
-    fn protocol(self, _: Context) -> Self::ProtocolFut {
-        info!("Method: protocol");
+    fn protocol(self, ctx: Context) -> Self::ProtocolFut {
+        info!("Method: protocol [{}]", ctx.trace_id());
+
+        let version = str!(PROTOCOL_VERSION);
+
+        if self.log_payloads {
+            payload::log_response("Protocol", &version);
+        }
+
+        future::ready(version)
+    }
+
+    type HandshakeFut = Ready<Result<String>>;
 
-        future::ready(str!(PROTOCOL_VERSION))
+    fn handshake(self, ctx: Context, client_protocol: String) -> Self::HandshakeFut {
+        info!("Method: handshake [{}]", ctx.trace_id());
+
+        if let Some(min_version) = self.min_client_protocol {
+            // Unparseable versions can't be shown to be new enough
+            let too_old = match client_protocol.parse::<u32>() {
+                Ok(version) => version < min_version,
+                Err(_) => true,
+            };
+
+            if too_old {
+                warn!(
+                    "Refusing client with protocol {} (minimum {})",
+                    client_protocol, min_version,
+                );
+
+                let error = ServerError::ProtocolTooOld.to_sendable();
+                return future::err(error);
+            }
+        }
+
+        let version = str!(PROTOCOL_VERSION);
+
+        if self.log_payloads {
+            payload::log_response("Handshake", &version);
+        }
+
+        future::ok(version)
     }
 
     type PingFut = BoxFuture<'static, Result<()>>;
 
-    fn ping(mut self, _: Context) -> Self::PingFut {
-        info!("Method: ping");
+    fn ping(mut self, ctx: Context) -> Self::PingFut {
+        info!("Method: ping [{}]", ctx.trace_id());
 
-        forward!(self, Ping)
+        throttle!(self, "ping");
+
+        forward!(self, ctx, Ping)
     }
 
     type TimeFut = Ready<f64>;
 
     #[inline]
-    fn time(self, _: Context) -> Self::TimeFut {
-        info!("Method: time");
+    fn time(self, ctx: Context) -> Self::TimeFut {
+        info!("Method: time [{}]", ctx.trace_id());
+
+        let now = unix_time(SystemTime::now());
 
-        let now = SystemTime::now();
-        let unix_time = now
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .expect("System time before epoch")
-            .as_secs_f64();
+        if self.log_payloads {
+            payload::log_response("Time", &now);
+        }
 
-        future::ready(unix_time)
+        future::ready(now)
+    }
+
+    type UptimeFut = Ready<Result<UptimeInfo>>;
+
+    #[inline]
+    fn uptime(self, ctx: Context) -> Self::UptimeFut {
+        info!("Method: uptime [{}]", ctx.trace_id());
+
+        let info = UptimeInfo {
+            start_time: self.start_time,
+            uptime: self.started.elapsed().as_secs_f64(),
+        };
+
+        if self.log_payloads {
+            payload::log_response("Uptime", &info);
+        }
+
+        future::ok(info)
+    }
+
+    type ReadinessFut = Ready<ReadinessInfo>;
+
+    #[inline]
+    fn readiness(self, ctx: Context) -> Self::ReadinessFut {
+        info!("Method: readiness [{}]", ctx.trace_id());
+
+        // Answered here rather than forwarded, so it still works while draining
+        let info = ReadinessInfo {
+            ready: !self.draining.load(Ordering::Acquire),
+            in_flight: self.in_flight.load(Ordering::Acquire) as u64,
+        };
+
+        if self.log_payloads {
+            payload::log_response("Readiness", &info);
+        }
+
+        future::ready(info)
+    }
+
+    type BatchLimitsFut = Ready<Result<BatchLimits>>;
+
+    #[inline]
+    fn batch_limits(self, ctx: Context) -> Self::BatchLimitsFut {
+        info!("Method: batch_limits [{}]", ctx.trace_id());
+
+        let limits = BatchLimits {
+            request_items: self.max_request_items,
+        };
+
+        if self.log_payloads {
+            payload::log_response("BatchLimits", &limits);
+        }
+
+        future::ok(limits)
     }
 
     // Sessions
@@ -180,15 +581,25 @@ impl DeepwellApi for Server {
 
     fn login(
         mut self,
-        _: Context,
+        ctx: Context,
         username_or_email: String,
         password: String,
         remote_address: Option<String>,
     ) -> Self::LoginFut {
-        info!("Method: login");
+        info!("Method: login [{}]", ctx.trace_id());
+
+        throttle!(self, "login");
+        writable!(self);
+
+        // Record the connection's address if the client didn't give one
+        let remote_address = match remote_address {
+            Some(address) => Some(address),
+            None => self.peer_addr.map(|addr| addr.ip().to_string()),
+        };
 
         forward!(
             self,
+            ctx,
             TryLogin,
             [username_or_email, password, remote_address],
         )
@@ -196,109 +607,515 @@ impl DeepwellApi for Server {
 
     type LogoutFut = BoxFuture<'static, Result<()>>;
 
-    fn logout(mut self, _: Context, session_id: SessionId, user_id: UserId) -> Self::LogoutFut {
-        info!("Method: logout");
+    fn logout(mut self, ctx: Context, session_id: SessionId, user_id: UserId) -> Self::LogoutFut {
+        info!("Method: logout [{}]", ctx.trace_id());
+
+        throttle!(self, "logout");
+        writable!(self);
 
-        forward!(self, Logout, [session_id, user_id])
+        let session_events = Arc::clone(&self.session_events);
+
+        forward!(self, ctx, Logout, [session_id, user_id])
+            .map_ok(move |()| {
+                session_events.publish(SessionInvalidated {
+                    session_id,
+                    user_id,
+                })
+            })
+            .boxed()
     }
 
     type LogoutOthersFut = BoxFuture<'static, Result<Vec<Session>>>;
 
     fn logout_others(
         mut self,
-        _: Context,
+        ctx: Context,
         session_id: SessionId,
         user_id: UserId,
     ) -> Self::LogoutOthersFut {
-        info!("Method: logout_others");
+        info!("Method: logout_others [{}]", ctx.trace_id());
+
+        throttle!(self, "logout_others");
+        writable!(self);
+
+        let session_events = Arc::clone(&self.session_events);
 
-        forward!(self, LogoutOthers, [session_id, user_id])
+        forward!(self, ctx, LogoutOthers, [session_id, user_id])
+            .map_ok(move |sessions| {
+                for session in &sessions {
+                    session_events.publish(SessionInvalidated {
+                        session_id: session.session_id(),
+                        user_id,
+                    });
+                }
+
+                sessions
+            })
+            .boxed()
     }
 
     type CheckSessionFut = BoxFuture<'static, Result<()>>;
 
     fn check_session(
         mut self,
-        _: Context,
+        ctx: Context,
         session_id: SessionId,
         user_id: UserId,
     ) -> Self::CheckSessionFut {
-        info!("Method: check_session");
+        info!("Method: check_session [{}]", ctx.trace_id());
 
-        forward!(self, CheckSession, [session_id, user_id])
+        throttle!(self, "check_session");
+
+        forward!(self, ctx, CheckSession, [session_id, user_id])
     }
 
+    type PollSessionEventsFut = BoxFuture<'static, Result<EventBatch<SessionInvalidated>>>;
+
+    fn poll_session_events(
+        mut self,
+        ctx: Context,
+        session_id: SessionId,
+        user_id: UserId,
+        cursor: Option<u64>,
+    ) -> Self::PollSessionEventsFut {
+        info!("Method: poll_session_events [{}]", ctx.trace_id());
+
+        throttle!(self, "poll_session_events");
+
+        let session_events = Arc::clone(&self.session_events);
+        let wait = poll_wait(ctx.deadline);
+        let check = forward!(self, ctx, CheckSession, [session_id, user_id]);
+
+        let fut = async move {
+            check.await?;
+
+            // Only the user's own sessions, other users' session IDs are secret
+            let filter = |event: &SessionInvalidated| event.user_id == user_id;
+            let (cursor, events) = session_events.poll(cursor, wait, filter).await;
+
+            Ok(EventBatch { cursor, events })
+        };
+
+        fut.boxed()
+    }
+
+    // User
+
     type CreateUserFut = BoxFuture<'static, Result<UserId>>;
 
     fn create_user(
         mut self,
-        _: Context,
+        ctx: Context,
         name: String,
         email: String,
         password: String,
     ) -> Self::CreateUserFut {
-        info!("Method: create_user");
+        info!("Method: create_user [{}]", ctx.trace_id());
+
+        throttle!(self, "create_user");
+        writable!(self);
 
-        forward!(self, CreateUser, [name, email, password])
+        forward!(self, ctx, CreateUser, [name, email, password])
     }
 
     type EditUserFut = BoxFuture<'static, Result<()>>;
 
     fn edit_user(
         mut self,
-        _: Context,
+        ctx: Context,
         user_id: UserId,
         changes: UserMetadataOwned,
     ) -> Self::EditUserFut {
-        info!("Method: edit_user");
+        info!("Method: edit_user [{}]", ctx.trace_id());
 
-        forward!(self, EditUser, [user_id, changes])
+        throttle!(self, "edit_user");
+        writable!(self);
+
+        forward!(self, ctx, EditUser, [user_id, changes])
     }
 
     type GetUserFromIdFut = BoxFuture<'static, Result<Option<User>>>;
 
-    fn get_user_from_id(mut self, _: Context, user_id: UserId) -> Self::GetUserFromIdFut {
-        info!("Method: get_user_from_id");
+    fn get_user_from_id(mut self, ctx: Context, user_id: UserId) -> Self::GetUserFromIdFut {
+        info!("Method: get_user_from_id [{}]", ctx.trace_id());
+
+        throttle!(self, "get_user_from_id");
 
-        forward!(self, GetUserFromId, [user_id])
+        let user_reads = self.user_reads.clone();
+        let fut = forward!(self, ctx, GetUserFromId, [user_id]);
+
+        match user_reads {
+            Some(user_reads) => user_reads.run(user_id, fut),
+            None => fut,
+        }
     }
 
     type GetUsersFromIdsFut = BoxFuture<'static, Result<Vec<Option<User>>>>;
 
-    fn get_users_from_ids(mut self, _: Context, user_ids: Vec<UserId>) -> Self::GetUsersFromIdsFut {
-        info!("Method: get_users_from_ids");
-
-        forward!(self, GetUsersFromIds, [user_ids])
+    fn get_users_from_ids(
+        mut self,
+        ctx: Context,
+        user_ids: Vec<UserId>,
+    ) -> Self::GetUsersFromIdsFut {
+        info!("Method: get_users_from_ids [{}]", ctx.trace_id());
+
+        throttle!(self, "get_users_from_ids");
+        bounded!(self, user_ids);
+        forward!(self, ctx, GetUsersFromIds, [user_ids])
     }
 
     type GetUserFromNameFut = BoxFuture<'static, Result<Option<User>>>;
 
-    fn get_user_from_name(mut self, _: Context, name: String) -> Self::GetUserFromNameFut {
-        info!("Method: get_user_from_name");
+    fn get_user_from_name(mut self, ctx: Context, name: String) -> Self::GetUserFromNameFut {
+        info!("Method: get_user_from_name [{}]", ctx.trace_id());
+
+        throttle!(self, "get_user_from_name");
 
-        forward!(self, GetUserFromName, [name])
+        forward!(self, ctx, GetUserFromName, [name])
     }
 
     type GetUserFromEmailFut = BoxFuture<'static, Result<Option<User>>>;
 
-    fn get_user_from_email(mut self, _: Context, email: String) -> Self::GetUserFromEmailFut {
-        info!("Method: get_user_from_email");
+    fn get_user_from_email(mut self, ctx: Context, email: String) -> Self::GetUserFromEmailFut {
+        info!("Method: get_user_from_email [{}]", ctx.trace_id());
 
-        forward!(self, GetUserFromEmail, [email])
+        throttle!(self, "get_user_from_email");
+
+        forward!(self, ctx, GetUserFromEmail, [email])
     }
 
+    type IsUsernameAvailableFut = BoxFuture<'static, Result<bool>>;
+
+    fn is_username_available(mut self, ctx: Context, name: String) -> Self::IsUsernameAvailableFut {
+        info!("Method: is_username_available [{}]", ctx.trace_id());
+
+        throttle!(self, "is_username_available");
+
+        forward!(self, ctx, IsUsernameAvailable, [name])
+    }
+
+    type IsEmailAvailableFut = BoxFuture<'static, Result<bool>>;
+
+    fn is_email_available(mut self, ctx: Context, email: String) -> Self::IsEmailAvailableFut {
+        info!("Method: is_email_available [{}]", ctx.trace_id());
+
+        throttle!(self, "is_email_available");
+
+        forward!(self, ctx, IsEmailAvailable, [email])
+    }
+
+    type CreateUsersFut = BoxFuture<'static, Result<Vec<Result<UserId>>>>;
+
+    fn create_users(mut self, ctx: Context, users: Vec<NewUser>) -> Self::CreateUsersFut {
+        info!("Method: create_users [{}]", ctx.trace_id());
+
+        throttle!(self, "create_users");
+        writable!(self);
+        bounded!(self, users);
+
+        forward!(self, ctx, CreateUsers, [users])
+    }
+
+    type IsEmailVerifiedFut = BoxFuture<'static, Result<bool>>;
+
+    fn is_email_verified(mut self, ctx: Context, user_id: UserId) -> Self::IsEmailVerifiedFut {
+        info!("Method: is_email_verified [{}]", ctx.trace_id());
+
+        throttle!(self, "is_email_verified");
+
+        forward!(self, ctx, IsEmailVerified, [user_id])
+    }
+
+    type GetOrCreateUserFut = BoxFuture<'static, Result<(UserId, bool)>>;
+
+    fn get_or_create_user(
+        mut self,
+        ctx: Context,
+        name: String,
+        email: String,
+        password: String,
+    ) -> Self::GetOrCreateUserFut {
+        info!("Method: get_or_create_user [{}]", ctx.trace_id());
+
+        throttle!(self, "get_or_create_user");
+        writable!(self);
+
+        forward!(self, ctx, GetOrCreateUser, [name, email, password])
+    }
+
+    // Wiki
+
+    // Page
+
     type GetPageContentsFut = BoxFuture<'static, Result<Option<String>>>;
 
     fn get_page_contents(
         mut self,
-        _: Context,
+        ctx: Context,
         wiki_id: WikiId,
         slug: String,
     ) -> Self::GetPageContentsFut {
-        info!("Method: get_page_contents");
+        info!("Method: get_page_contents [{}]", ctx.trace_id());
+
+        throttle!(self, "get_page_contents");
 
-        forward!(self, GetPageContents, [wiki_id, slug])
+        let page_reads = self.page_reads.clone();
+        let key = (wiki_id, slug.clone());
+        let fut = forward!(self, ctx, GetPageContents, [wiki_id, slug]);
+
+        match page_reads {
+            Some(page_reads) => page_reads.run(key, fut),
+            None => fut,
+        }
+    }
+
+    type GetPageContentsIfChangedFut = BoxFuture<'static, Result<PageContentsResult>>;
+
+    fn get_page_contents_if_changed(
+        mut self,
+        ctx: Context,
+        wiki_id: WikiId,
+        slug: String,
+        known_tag: Option<u64>,
+    ) -> Self::GetPageContentsIfChangedFut {
+        info!("Method: get_page_contents_if_changed [{}]", ctx.trace_id());
+
+        throttle!(self, "get_page_contents_if_changed");
+
+        forward!(
+            self,
+            ctx,
+            GetPageContentsIfChanged,
+            [wiki_id, slug, known_tag]
+        )
+    }
+
+    // Administration
+
+    type SetReadOnlyFut = BoxFuture<'static, Result<()>>;
+
+    fn set_read_only(
+        self,
+        ctx: Context,
+        admin_session_id: SessionId,
+        admin_user_id: UserId,
+        read_only: bool,
+    ) -> Self::SetReadOnlyFut {
+        info!("Method: set_read_only [{}]", ctx.trace_id());
+
+        throttle!(self, "set_read_only");
+
+        let read_only_flag = Arc::clone(&self.read_only);
+        let check = check_admin!(self, ctx, admin_session_id, admin_user_id);
+
+        let fut = async move {
+            check.await?;
+
+            warn!("Setting read-only mode to {}", read_only);
+            read_only_flag.store(read_only, Ordering::Release);
+
+            Ok(())
+        };
+
+        fut.boxed()
+    }
+
+    type LogoutSessionsFut = BoxFuture<'static, Result<Vec<Result<()>>>>;
+
+    fn logout_sessions(
+        mut self,
+        ctx: Context,
+        admin_session_id: SessionId,
+        admin_user_id: UserId,
+        sessions: Vec<(SessionId, UserId)>,
+    ) -> Self::LogoutSessionsFut {
+        info!("Method: logout_sessions [{}]", ctx.trace_id());
+
+        throttle!(self, "logout_sessions");
+        writable!(self);
+        bounded!(self, sessions);
+
+        let session_events = Arc::clone(&self.session_events);
+        let ended = sessions.clone();
+        let check = check_admin!(self, ctx, admin_session_id, admin_user_id);
+        let logout = forward!(self, ctx, LogoutSessions, [sessions]);
+
+        let fut = async move {
+            check.await?;
+
+            let results = logout.await?;
+            for (&(session_id, user_id), result) in ended.iter().zip(&results) {
+                if result.is_ok() {
+                    session_events.publish(SessionInvalidated {
+                        session_id,
+                        user_id,
+                    });
+                }
+            }
+
+            Ok(results)
+        };
+
+        fut.boxed()
+    }
+
+    type SetLogLevelFut = BoxFuture<'static, Result<()>>;
+
+    fn set_log_level(
+        self,
+        ctx: Context,
+        admin_session_id: SessionId,
+        admin_user_id: UserId,
+        level: String,
+    ) -> Self::SetLogLevelFut {
+        info!("Method: set_log_level [{}]", ctx.trace_id());
+
+        throttle!(self, "set_log_level");
+
+        let check = check_admin!(self, ctx, admin_session_id, admin_user_id);
+
+        let fut = async move {
+            check.await?;
+
+            let level = match find_log_level(&level) {
+                Some(level) => level,
+                None => return Err(DeepwellError::StaticMsg("no such log level").to_sendable()),
+            };
+
+            warn!("Changing log level to {}", level);
+            log::set_max_level(level);
+
+            Ok(())
+        };
+
+        fut.boxed()
     }
 
     // TODO
 }
+
+/// Logs and configures a newly accepted connection.
+fn accepted(conn: &TcpStream, connection_log_level: Option<Level>, tcp_nodelay: bool) {
+    match conn.peer_addr() {
+        Ok(addr) => {
+            if let Some(level) = connection_log_level {
+                log!(level, "Accepted connection from {}", addr);
+            }
+        }
+        Err(error) => warn!("Unable to get peer address: {}", error),
+    }
+
+    if let Err(error) = conn.set_nodelay(tcp_nodelay) {
+        warn!("Unable to set TCP_NODELAY on connection: {}", error);
+    }
+}
+
+/// How long a long poll can wait while still answering before the deadline.
+fn poll_wait(deadline: SystemTime) -> Duration {
+    let remaining = deadline
+        .duration_since(SystemTime::now())
+        .unwrap_or_default();
+
+    remaining.checked_sub(POLL_MARGIN).unwrap_or_default()
+}
+
+fn unix_time(time: SystemTime) -> f64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .expect("System time before epoch")
+        .as_secs_f64()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::MockServer;
+    use tarpc::rpc::context;
+
+    #[tokio::test]
+    async fn drain() {
+        let server = MockServer::start(&Config::testing()).await.unwrap();
+        let mut client = server.connect_raw().await;
+
+        client.ping(context::current()).await.unwrap().unwrap();
+        assert_eq!(server.server().drain(Duration::from_millis(10)).await, 0);
+
+        let error = client.ping(context::current()).await.unwrap().unwrap_err();
+        assert_eq!(
+            ServerError::from_sendable(&error),
+            Some(ServerError::ShuttingDown),
+        );
+
+        let info = client.readiness(context::current()).await.unwrap();
+        assert!(!info.ready);
+        assert_eq!(info.in_flight, 0);
+    }
+
+    #[tokio::test]
+    async fn client_rate_limits() {
+        let mut config = Config::testing();
+        config.client_rate_limits.insert(str!("ping"), 2.0);
+
+        let server = MockServer::start(&config).await.unwrap();
+        let mut client = server.connect_raw().await;
+
+        client.ping(context::current()).await.unwrap().unwrap();
+        client.ping(context::current()).await.unwrap().unwrap();
+
+        let error = client.ping(context::current()).await.unwrap().unwrap_err();
+        assert_eq!(
+            ServerError::from_sendable(&error),
+            Some(ServerError::RateLimited),
+        );
+
+        // Other methods are unaffected
+        let available = client
+            .is_username_available(context::current(), str!("squirrelbird"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(available);
+    }
+
+    #[tokio::test]
+    async fn handshake_min_protocol() {
+        let mut config = Config::testing();
+        config.min_client_protocol = Some(3);
+
+        let server = MockServer::start(&config).await.unwrap();
+        let mut client = server.connect_raw().await;
+
+        for version in &["3", "4"] {
+            let result = client.handshake(context::current(), str!(version)).await;
+            assert_eq!(result.unwrap(), Ok(str!(PROTOCOL_VERSION)));
+        }
+
+        for version in &["2", "not-a-number"] {
+            let result = client.handshake(context::current(), str!(version)).await;
+            let error = result.unwrap().unwrap_err();
+            assert_eq!(
+                ServerError::from_sendable(&error),
+                Some(ServerError::ProtocolTooOld),
+            );
+        }
+
+        // Without a minimum, any version is accepted
+        let server = MockServer::start(&Config::testing()).await.unwrap();
+        let mut client = server.connect_raw().await;
+        let result = client.handshake(context::current(), str!("0")).await;
+        assert_eq!(result.unwrap(), Ok(str!(PROTOCOL_VERSION)));
+    }
+
+    #[tokio::test]
+    async fn tcp_nodelay() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        for &value in &[true, false] {
+            let _client = TcpStream::connect(address).await.unwrap();
+            let (conn, _) = listener.accept().await.unwrap();
+
+            accepted(&conn, None, value);
+            assert_eq!(conn.nodelay().unwrap(), value);
+        }
+    }
+}
@@ -0,0 +1,102 @@
+/*
+ * stats.rs
+ *
+ * deepwell-rpc - RPC server to provide database management and migrations
+ * Copyright (C) 2019-2020 Ammon Smith
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Counters kept by the client about the calls it has made.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+#[derive(Debug, Default, Clone)]
+pub struct MethodStats {
+    /// How many times this method was called.
+    pub calls: u64,
+
+    /// How many extra attempts were made after a timeout or lost connection.
+    pub retries: u64,
+
+    /// Total time spent in calls to this method, including retries.
+    pub total_time: Duration,
+}
+
+impl MethodStats {
+    /// Average time spent per call, or zero if the method was never called.
+    pub fn average_time(&self) -> Duration {
+        if self.calls == 0 {
+            return Duration::from_secs(0);
+        }
+
+        self.total_time.div_f64(self.calls as f64)
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ClientStats {
+    methods: HashMap<&'static str, MethodStats>,
+    reconnects: u64,
+}
+
+impl ClientStats {
+    #[inline]
+    pub fn method(&self, name: &str) -> Option<&MethodStats> {
+        self.methods.get(name)
+    }
+
+    #[inline]
+    pub fn methods(&self) -> impl Iterator<Item = (&'static str, &MethodStats)> {
+        self.methods.iter().map(|(name, stats)| (*name, stats))
+    }
+
+    /// Total number of calls made, across all methods.
+    pub fn calls(&self) -> u64 {
+        self.methods.values().map(|stats| stats.calls).sum()
+    }
+
+    /// Total number of retries made, across all methods.
+    pub fn retries(&self) -> u64 {
+        self.methods.values().map(|stats| stats.retries).sum()
+    }
+
+    #[inline]
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects
+    }
+
+    // Recording
+
+    #[inline]
+    pub(crate) fn record_call(&mut self, name: &'static str) {
+        self.methods.entry(name).or_default().calls += 1;
+    }
+
+    #[inline]
+    pub(crate) fn record_retry(&mut self, name: &'static str) {
+        self.methods.entry(name).or_default().retries += 1;
+    }
+
+    #[inline]
+    pub(crate) fn record_time(&mut self, name: &'static str, elapsed: Duration) {
+        self.methods.entry(name).or_default().total_time += elapsed;
+    }
+
+    #[inline]
+    pub(crate) fn record_reconnect(&mut self) {
+        self.reconnects += 1;
+    }
+}
@@ -0,0 +1,99 @@
+/*
+ * transport.rs
+ *
+ * deepwell-rpc - RPC server to provide database management and migrations
+ * Copyright (C) 2019-2020 Ammon Smith
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Choice of codec used to frame messages on each connection.
+//!
+//! The server and client both take a `TransportFactory`, so a different
+//! wire format can be used without changing either of them.
+
+use bytes::{Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Debug};
+use std::io;
+use std::pin::Pin;
+use tokio_serde::formats::Json;
+use tokio_serde::{Deserializer, Serializer};
+
+/// Builds the codec for a new connection, which reads `Item` and writes `SinkItem`.
+///
+/// Codec errors are reported as `io::Error`, which is what the transport expects.
+pub trait TransportFactory<Item, SinkItem>: Debug + Send + Sync + 'static {
+    type Codec: Serializer<SinkItem, Error = io::Error>
+        + Deserializer<Item, Error = io::Error>
+        + Unpin
+        + Send
+        + 'static;
+
+    fn codec(&self) -> Self::Codec;
+}
+
+/// Frames messages as JSON. This is the default.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct JsonTransport;
+
+impl<Item, SinkItem> TransportFactory<Item, SinkItem> for JsonTransport
+where
+    Item: for<'de> Deserialize<'de> + Unpin + Send + 'static,
+    SinkItem: Serialize + Unpin + Send + 'static,
+{
+    type Codec = JsonCodec<Item, SinkItem>;
+
+    #[inline]
+    fn codec(&self) -> Self::Codec {
+        JsonCodec(Json::default())
+    }
+}
+
+/// The JSON codec, with its errors converted to `io::Error`.
+pub struct JsonCodec<Item, SinkItem>(Json<Item, SinkItem>);
+
+impl<Item, SinkItem> Debug for JsonCodec<Item, SinkItem> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("JsonCodec")
+    }
+}
+
+impl<Item, SinkItem> Serializer<SinkItem> for JsonCodec<Item, SinkItem>
+where
+    Item: Unpin,
+    SinkItem: Serialize + Unpin,
+{
+    type Error = io::Error;
+
+    fn serialize(self: Pin<&mut Self>, item: &SinkItem) -> io::Result<Bytes> {
+        let codec = Pin::new(&mut self.get_mut().0);
+
+        codec.serialize(item).map_err(io::Error::from)
+    }
+}
+
+impl<Item, SinkItem> Deserializer<Item> for JsonCodec<Item, SinkItem>
+where
+    Item: for<'de> Deserialize<'de> + Unpin,
+    SinkItem: Unpin,
+{
+    type Error = io::Error;
+
+    fn deserialize(self: Pin<&mut Self>, src: &BytesMut) -> io::Result<Item> {
+        let codec = Pin::new(&mut self.get_mut().0);
+
+        codec.deserialize(src).map_err(io::Error::from)
+    }
+}